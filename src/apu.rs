@@ -5,11 +5,12 @@ use std::sync::Mutex;
 use once_cell::sync::Lazy;
 
 use crate::io::*;
-use crate::events::*;
 use crate::apu_frame::*;
 use crate::apu_square::*;
 use crate::apu_triangle::*;
 use crate::apu_noise::*;
+use crate::state::*;
+use std::io;
 
 const CLOCK_DIV_FRAME: i32 = 7457;
 const CLOCK_FQ: u32 = 1789772; // NTSC
@@ -95,7 +96,7 @@ pub struct APU {
 }
 
 impl APU {
-	pub fn new(io:Arc<Mutex<IO>>, event_queue: Arc<Mutex<EventQueue>>) -> APU {
+	pub fn new(io:Arc<Mutex<IO>>) -> APU {
 		let square1 = Rc::new(RefCell::new(APUSquare::new(1)));
 		let square2 = Rc::new(RefCell::new(APUSquare::new(2)));
 		let triangle = Rc::new(RefCell::new(APUTriangle::new()));
@@ -130,8 +131,7 @@ impl APU {
 				Rc::clone(&square1),
 				Rc::clone(&square2),
 				Rc::clone(&triangle),
-				Rc::clone(&noise),
-				event_queue
+				Rc::clone(&noise)
 			),
 			square1: square1,
 			square2: square2,
@@ -148,6 +148,17 @@ impl APU {
 		// TODO
 	}
 
+	// Every register and channel back to the power-on state
+	pub fn power_on(&mut self) {
+		*self = APU::new(Arc::clone(&self.io));
+	}
+
+	// IRQ output, held until acknowledged by reading $4015 or inhibited via $4017
+	// TODO: DMC IRQ
+	pub fn irq(&self) -> bool {
+		return self.frame.interrupted;
+	}
+
 	pub fn clock(&mut self) {
 		if !self.stall {
 			if self.clock_flg {
@@ -244,6 +255,14 @@ impl APU {
 	}
 
 	pub fn get_ch_ctrl(&mut self) -> u8 {
+		let ret = self.peek_ch_ctrl();
+		// reading acknowledges the frame interrupt
+		self.frame.interrupted = false;
+		return ret;
+	}
+
+	// $4015 without acknowledging the frame interrupt
+	pub fn peek_ch_ctrl(&self) -> u8 {
 		let mut ret:u8 = 0;
 
 		if self.square1.borrow().get_length_counter() != 0 {
@@ -260,7 +279,6 @@ impl APU {
 		}
 		if self.frame.interrupted {
 			ret |= CH_CTRL_INTERRUPT;
-			self.frame.interrupted = false;
 		}
 
 		// TODO: DMC
@@ -304,6 +322,63 @@ impl APU {
 		self.frame_cr = self.frame.set_cr(v);
 		self.clock_frame = CLOCK_DIV_FRAME -1;
 	}
+	pub fn save_state(&self, w: &mut StateWriter) {
+		let regs = [
+			self.sw1c1, self.sw1c2, self.sw1fq1, self.sw1fq2,
+			self.sw2c1, self.sw2c2, self.sw2fq1, self.sw2fq2,
+			self.twc, self.twfq1, self.twfq2,
+			self.nc, self.nfq1, self.nfq2,
+			self.dmc1, self.dmc2, self.dmc3, self.dmc4,
+			self.ch_ctrl, self.frame_cr,
+		];
+		w.write_bytes(&regs);
+		w.write_i32(self.clock_frame);
+		w.write_bool(self.clock_flg);
+		w.write_u32(self.render_clock);
+		w.write_bool(self.stall);
+
+		self.frame.save_state(w);
+		self.square1.borrow().save_state(w);
+		self.square2.borrow().save_state(w);
+		self.triangle.borrow().save_state(w);
+		self.noise.borrow().save_state(w);
+	}
+
+	pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		let mut regs = [0; 20];
+		r.read_bytes(&mut regs)?;
+		self.sw1c1 = regs[0];
+		self.sw1c2 = regs[1];
+		self.sw1fq1 = regs[2];
+		self.sw1fq2 = regs[3];
+		self.sw2c1 = regs[4];
+		self.sw2c2 = regs[5];
+		self.sw2fq1 = regs[6];
+		self.sw2fq2 = regs[7];
+		self.twc = regs[8];
+		self.twfq1 = regs[9];
+		self.twfq2 = regs[10];
+		self.nc = regs[11];
+		self.nfq1 = regs[12];
+		self.nfq2 = regs[13];
+		self.dmc1 = regs[14];
+		self.dmc2 = regs[15];
+		self.dmc3 = regs[16];
+		self.dmc4 = regs[17];
+		self.ch_ctrl = regs[18];
+		self.frame_cr = regs[19];
+		self.clock_frame = r.read_i32()?;
+		self.clock_flg = r.read_bool()?;
+		self.render_clock = r.read_u32()?;
+		self.stall = r.read_bool()?;
+
+		self.frame.load_state(r)?;
+		self.square1.borrow_mut().load_state(r)?;
+		self.square2.borrow_mut().load_state(r)?;
+		self.triangle.borrow_mut().load_state(r)?;
+		self.noise.borrow_mut().load_state(r)?;
+		return Ok(());
+	}
 /*
 	fn genrate_lut() {
 		for x in 0..256 {
@@ -1,3 +1,6 @@
+use std::io;
+use crate::state::*;
+
 pub struct APUEnvelope {
 	val: u8,
 	cr: u8,
@@ -50,6 +53,21 @@ impl APUEnvelope {
 		self.reset = true;
 	}
 
+	pub fn save_state(&self, w: &mut StateWriter) {
+		w.write_u8(self.val);
+		w.write_u8(self.cr);
+		w.write_bool(self.reset);
+		w.write_u8(self.clock);
+	}
+
+	pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		self.val = r.read_u8()?;
+		self.cr = r.read_u8()?;
+		self.reset = r.read_bool()?;
+		self.clock = r.read_u8()?;
+		return Ok(());
+	}
+
 	pub fn val(&self) -> u8 {
 		if self.cr & OFF_MASK == 0 {
 			return self.val;
@@ -1,11 +1,10 @@
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::sync::Arc;
-use std::sync::Mutex;
 use crate::apu_square::*;
 use crate::apu_triangle::*;
 use crate::apu_noise::*;
-use crate::events::*;
+use crate::state::*;
+use std::io;
 
 const SEQ_MODE_MASK: u8 = 0x80;
 const NO_IRQ_MASK:u8 = 0x40;
@@ -19,7 +18,6 @@ pub struct APUFrame {
 	square2: Rc<RefCell<APUSquare>>,
 	triangle: Rc<RefCell<APUTriangle>>,
 	noise: Rc<RefCell<APUNoise>>,
-	event_queue: Arc<Mutex<EventQueue>>
 }
 
 impl APUFrame {
@@ -27,8 +25,7 @@ impl APUFrame {
 			square1: Rc<RefCell<APUSquare>>,
 			square2: Rc<RefCell<APUSquare>>,
 			triangle: Rc<RefCell<APUTriangle>>,
-			noise: Rc<RefCell<APUNoise>>,
-			event_queue: Arc<Mutex<EventQueue>>
+			noise: Rc<RefCell<APUNoise>>
 		) -> APUFrame {
 		APUFrame {
 			interrupted: false,
@@ -39,7 +36,6 @@ impl APUFrame {
 			square2: square2,
 			triangle: triangle,
 			noise: noise,
-			event_queue: event_queue,
 		}
 	}
 
@@ -92,8 +88,6 @@ impl APUFrame {
 					self.noise.borrow_mut().envelope_clock();
 
 					if self.cr & NO_IRQ_MASK == 0 {
-						let mut queue = self.event_queue.lock().unwrap();
-						queue.push(Event::new(EventType::IRQ));
 						self.interrupted = true;
 					}
 				}
@@ -166,6 +160,22 @@ impl APUFrame {
 
 	pub fn set_cr(&mut self, v: u8) -> u8 {
 		self.cr = v;
+		if self.cr & NO_IRQ_MASK != 0 {
+			self.interrupted = false;
+		}
 		return self.cr;
 	}
+
+	pub fn save_state(&self, w: &mut StateWriter) {
+		w.write_bool(self.interrupted);
+		w.write_u8(self.cr);
+		w.write_u8(self.seq);
+	}
+
+	pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		self.interrupted = r.read_bool()?;
+		self.cr = r.read_u8()?;
+		self.seq = r.read_u8()?;
+		return Ok(());
+	}
 }
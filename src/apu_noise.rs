@@ -2,6 +2,8 @@ use crate::apu::LENGTH_COUNTER_LUT;
 use crate::apu::U4_2_F32_LUT;
 use crate::apu::CH_CTRL_NOISE;
 use crate::apu_envelope::*;
+use crate::state::*;
+use std::io;
 
 const RAND_MODE:u8 = 0x80;
 const LENGTH_COUNTER_OFF_MASK:u8 = 0x20;
@@ -97,6 +99,31 @@ impl APUNoise {
 		return self.length_counter;
 	}
 
+	pub fn save_state(&self, w: &mut StateWriter) {
+		w.write_f32(self.val);
+		w.write_u8(self.cr);
+		w.write_u8(self.fq1);
+		w.write_u8(self.fq2);
+		w.write_u8(self.length_counter);
+		w.write_u16(self.shift_reg);
+		w.write_u16(self.clock);
+		w.write_u16(self.clock_div);
+		self.envelope.save_state(w);
+	}
+
+	pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		self.val = r.read_f32()?;
+		self.cr = r.read_u8()?;
+		self.fq1 = r.read_u8()?;
+		self.fq2 = r.read_u8()?;
+		self.length_counter = r.read_u8()?;
+		self.shift_reg = r.read_u16()?;
+		self.clock = r.read_u16()?;
+		self.clock_div = r.read_u16()?;
+		self.envelope.load_state(r)?;
+		return Ok(());
+	}
+
 	fn next_seq(&mut self) {
 		let mut exor:bool = false;
 		if (self.fq1 & RAND_MODE) == 0 {
@@ -1,6 +1,8 @@
 use crate::apu::LENGTH_COUNTER_LUT;
 use crate::apu::U4_2_F32_LUT;
 use crate::apu_envelope::*;
+use crate::state::*;
+use std::io;
 
 pub struct APUSquare{
 	pub val: f32,
@@ -177,6 +179,37 @@ impl APUSquare {
 		return self.length_counter;
 	}
 
+	pub fn save_state(&self, w: &mut StateWriter) {
+		w.write_f32(self.val);
+		w.write_u8(self.cr1);
+		w.write_u8(self.cr2);
+		w.write_u8(self.fq1);
+		w.write_u8(self.fq2);
+		w.write_u8(self.length_counter);
+		w.write_u16(self.clock);
+		w.write_u16(self.clock_div);
+		w.write_usize(self.seq);
+		self.envelope.save_state(w);
+		w.write_u8(self.sweep_clock);
+		w.write_bool(self.sweep_reset);
+	}
+
+	pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		self.val = r.read_f32()?;
+		self.cr1 = r.read_u8()?;
+		self.cr2 = r.read_u8()?;
+		self.fq1 = r.read_u8()?;
+		self.fq2 = r.read_u8()?;
+		self.length_counter = r.read_u8()?;
+		self.clock = r.read_u16()?;
+		self.clock_div = r.read_u16()?;
+		self.seq = r.read_usize()? % 8;
+		self.envelope.load_state(r)?;
+		self.sweep_clock = r.read_u8()?;
+		self.sweep_reset = r.read_bool()?;
+		return Ok(());
+	}
+
 	fn next_seq(&mut self) {
 		let seq:&[u8;8];
 		match self.cr1 & DUTY_MASK {
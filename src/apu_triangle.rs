@@ -4,6 +4,8 @@ use std::sync::Mutex;
 use crate::apu::LENGTH_COUNTER_LUT;
 use crate::apu::CH_CTRL_TRIANGLE;
 use crate::apu::U8_2_F32_LUT;
+use crate::state::*;
+use std::io;
 
 const LENGTH_COUNTER_OFF_MASK:u8 = 0x80;
 
@@ -104,6 +106,33 @@ impl APUTriangle {
 		return self.length_counter;
 	}
 
+	pub fn save_state(&self, w: &mut StateWriter) {
+		w.write_f32(self.val);
+		w.write_u8(self.cr1);
+		w.write_u8(self.fq1);
+		w.write_u8(self.fq2);
+		w.write_u16(self.clock);
+		w.write_u16(self.clock_div);
+		w.write_u8(self.length_counter);
+		w.write_u8(self.linear_counter);
+		w.write_bool(self.linear_reload);
+		w.write_usize(self.seq);
+	}
+
+	pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		self.val = r.read_f32()?;
+		self.cr1 = r.read_u8()?;
+		self.fq1 = r.read_u8()?;
+		self.fq2 = r.read_u8()?;
+		self.clock = r.read_u16()?;
+		self.clock_div = r.read_u16()?;
+		self.length_counter = r.read_u8()?;
+		self.linear_counter = r.read_u8()?;
+		self.linear_reload = r.read_bool()?;
+		self.seq = r.read_usize()? % 32;
+		return Ok(());
+	}
+
 	fn next_seq(&mut self) {
 		let lut:[usize;32] = [
 			0xFF, 0xEE, 0xDD, 0xCC, 0xBB, 0xAA, 0x99, 0x88,
@@ -0,0 +1,243 @@
+extern crate rustnes;
+
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::path::Path;
+use std::process;
+
+use rustnes::Emulator;
+use rustnes::emulator::*;
+use rustnes::cartridge::*;
+use rustnes::disasm::*;
+use rustnes::screenshot::*;
+use rustnes::movie::*;
+use rustnes::trace::*;
+
+// Runs a cartridge for N frames without SDL, audio or frame pacing.
+// Prints the hash of the final frame and optionally dumps it as PNG/PPM.
+
+struct Configure {
+	cartridge: String,
+	frames: Option<u32>,
+	play: Option<String>,
+	output: Option<String>,
+	buttons: u8,
+	verbose: bool,
+	entry: Option<u16>,
+	trace: Option<String>,
+}
+
+fn main() {
+	let mut config = Configure {
+		cartridge: "".to_string(),
+		frames: None,
+		play: None,
+		output: None,
+		buttons: 0,
+		verbose: false,
+		entry: None,
+		trace: None,
+	};
+
+	let args:Vec<String> = env::args().collect();
+	if args.len() >= 2 && args[1] == "tracecmp" {
+		tracecmp(&args[2..]);
+		return;
+	}
+	if args.len() == 3 && args[1] == "disasm" {
+		disasm(&args[2]);
+		return;
+	}
+
+	analyze_arg(&mut config);
+	if config.cartridge.is_empty() {
+		println!("Usage: rustnes-headless [--frames N] [--play movie.fm2] [--out file.png|file.ppm] [--buttons hex] [--entry hex] [--trace file.log] [--verbose] cartridge");
+		println!("       rustnes-headless tracecmp [--registers] ours.log reference.log");
+		println!("       rustnes-headless disasm cartridge");
+		process::exit(2);
+	}
+
+	let rom = match fs::read(&config.cartridge) {
+		Err(e) => {
+			println!("{}: {}", config.cartridge, e);
+			process::exit(1);
+		}
+		Ok(d) => d,
+	};
+
+	let mut emu = Emulator::new();
+	if let Err(e) = emu.load_rom(&rom) {
+		println!("{}: {}", config.cartridge, e);
+		process::exit(1);
+	}
+
+	emu.set_buttons(0, config.buttons);
+	if let Some(entry) = config.entry {
+		emu.nes().set_pc(entry);
+	}
+	if let Some(path) = &config.trace {
+		match File::create(path) {
+			Err(e) => {
+				println!("{}: {}", path, e);
+				process::exit(1);
+			}
+			Ok(f) => emu.nes().set_trace(Some(Box::new(BufWriter::new(f)))),
+		}
+	}
+
+	// Without --frames, a movie runs to its end
+	let mut frames = config.frames.unwrap_or(60);
+	if let Some(path) = &config.play {
+		let movie = match Movie::load(path) {
+			Err(e) => {
+				println!("{}: {}", path, e);
+				process::exit(1);
+			}
+			Ok(m) => m,
+		};
+		frames = config.frames.unwrap_or(movie.frames.len() as u32);
+		emu.nes().play_movie(movie);
+	}
+
+	for _ in 0..frames {
+		emu.run_frame();
+		if config.verbose {
+			println!("frame {}: {:016x}", emu.frames(), frame_hash(emu.framebuffer()));
+		}
+	}
+
+	if let Some(path) = &config.output {
+		if let Err(e) = dump(path, emu.framebuffer()) {
+			println!("cannot write {}: {}", path, e);
+			process::exit(1);
+		}
+	}
+
+	emu.nes().set_trace(None);
+	println!("{:016x}", frame_hash(emu.framebuffer()));
+}
+
+// Disassemble PRG-ROM to stdout
+fn disasm(path:&str) {
+	let cartridge = fs::read(path)
+		.map_err(|e| e.to_string())
+		.and_then(|data| Cartridge::parse(&data).map_err(|e| e.to_string()));
+	match cartridge {
+		Err(e) => {
+			eprintln!("{}: {}", path, e);
+			process::exit(1);
+		}
+		Ok(c) => {
+			let stdout = io::stdout();
+			let mut w = BufWriter::new(stdout.lock());
+			if let Err(e) = disassemble_prg(&mut w, &c.prom) {
+				eprintln!("{}", e);
+				process::exit(1);
+			}
+		}
+	}
+}
+
+// Report the first line of our trace that differs from a reference log (e.g. nestest.log)
+fn tracecmp(args: &[String]) {
+	let registers_only = args.iter().any(|a| a == "--registers");
+	let files: Vec<&String> = args.iter().filter(|a| !a.starts_with("--")).collect();
+	if files.len() != 2 {
+		println!("Usage: rustnes-headless tracecmp [--registers] ours.log reference.log");
+		process::exit(2);
+	}
+
+	let open = |path: &str| match File::open(path) {
+		Err(e) => {
+			println!("{}: {}", path, e);
+			process::exit(2);
+		}
+		Ok(f) => BufReader::new(f),
+	};
+	match compare_trace(open(files[0]), open(files[1]), registers_only) {
+		Err(e) => {
+			println!("{}", e);
+			process::exit(2);
+		}
+		Ok(None) => {
+			println!("OK");
+		}
+		Ok(Some(diff)) => {
+			println!("line {}:", diff.line);
+			println!("  ours: {}", diff.ours);
+			println!("  ref:  {}", diff.reference);
+			process::exit(1);
+		}
+	}
+}
+
+fn dump(path: &str, rgb: &[u8]) -> std::io::Result<()> {
+	let mut w = BufWriter::new(File::create(path)?);
+	match Path::new(path).extension().and_then(|e| e.to_str()) {
+		Some("ppm") => write_ppm(&mut w, SCREEN_WIDTH, SCREEN_HEIGHT, rgb),
+		_ => write_png(&mut w, SCREEN_WIDTH, SCREEN_HEIGHT, rgb),
+	}
+}
+
+fn analyze_arg(config:&mut Configure) {
+	let args:Vec<String> = env::args().collect();
+
+	enum Option {
+		NONE,
+		FRAMES,
+		PLAY,
+		OUT,
+		BUTTONS,
+		ENTRY,
+		TRACE,
+	}
+	let mut option = Option::NONE;
+	for arg in args.into_iter().skip(1) {
+		match option {
+			Option::FRAMES => {
+				config.frames = Some(arg.parse().unwrap_or_else(|_| {
+					println!("bad frame count: {}", arg);
+					process::exit(2);
+				}));
+			}
+			Option::PLAY => {
+				config.play = Some(arg);
+			}
+			Option::OUT => {
+				config.output = Some(arg);
+			}
+			Option::ENTRY => {
+				config.entry = Some(u16::from_str_radix(&arg, 16).unwrap_or_else(|_| {
+					println!("bad address: {}", arg);
+					process::exit(2);
+				}));
+			}
+			Option::TRACE => {
+				config.trace = Some(arg);
+			}
+			Option::BUTTONS => {
+				config.buttons = u8::from_str_radix(&arg, 16).unwrap_or_else(|_| {
+					println!("bad button mask: {}", arg);
+					process::exit(2);
+				});
+			}
+			Option::NONE => {
+				match &*arg {
+					"--frames" => { option = Option::FRAMES; continue; }
+					"--play" => { option = Option::PLAY; continue; }
+					"--out" => { option = Option::OUT; continue; }
+					"--buttons" => { option = Option::BUTTONS; continue; }
+					"--entry" => { option = Option::ENTRY; continue; }
+					"--trace" => { option = Option::TRACE; continue; }
+					"--verbose" => { config.verbose = true; }
+					_ => { config.cartridge = arg; }
+				}
+			}
+		}
+		option = Option::NONE;
+	}
+}
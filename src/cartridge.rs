@@ -0,0 +1,289 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::mapper::*;
+use crate::screenshot::crc32;
+
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PROM_UNIT: usize = 16 * 1024;
+const CROM_UNIT: usize = 8 * 1024;
+const MAGIC: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A]; // "NES" EOF
+
+// Flags 6
+const FLAG6_MIRROR: u8             = 0x01;
+const FLAG6_HAS_BATTARY_BACKUP: u8 = 0x02;
+const FLAG6_HAS_TRAINER: u8        = 0x04;
+const FLAG6_FOUR_SCREEN: u8        = 0x08;
+const FLAG6_MAPPAER_LOW: u8        = 0xF0;
+
+// Flags 7
+const FLAG7_CONSOLE_TYPE: u8       = 0x03;
+const FLAG7_NES_2_0_MASK: u8       = 0x0C;
+const FLAG7_NES_2_0: u8            = 0x08;
+const FLAG7_MAPPER_HIGH: u8        = 0xF0;
+
+// iNES 1.0 Flags 9
+const FLAG9_PAL: u8                = 0x01;
+
+// NES 2.0 Byte 8
+const BYTE8_MAPPER_MSB: u8         = 0x0F;
+const BYTE8_SUBMAPPER: u8          = 0xF0;
+
+// NES 2.0 Byte 12
+const BYTE12_TIMING: u8            = 0x03;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+	BadMagic,
+	Truncated { expected: usize, actual: usize },
+	UnsupportedMapper(u16),
+	BadPrgSize(usize),
+	BadChrSize(usize),
+	Io(io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timing {
+	NTSC,
+	PAL,
+	MULTI,
+	DENDY,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleType {
+	NES,
+	VS,
+	PLAYCHOICE,
+	EXTENDED(u8),
+}
+
+#[derive(Debug, Clone)]
+pub struct RomHeader {
+	pub nes2: bool,
+	pub mapper: u16,
+	pub submapper: u8,
+
+	pub prom_size: usize,  // [bytes]
+	pub crom_size: usize,  // [bytes], 0: CHR-RAM
+	pub prg_ram_size: usize,
+	pub prg_nvram_size: usize,
+	pub chr_ram_size: usize,
+	pub chr_nvram_size: usize,
+
+	pub vertical_mirror: bool,
+	pub four_screen: bool,
+	pub battery: bool,
+	pub trainer: bool,
+
+	pub timing: Timing,
+	pub console_type: ConsoleType,
+}
+
+pub struct Cartridge {
+	pub header: RomHeader,
+	pub trainer: Vec<u8>,
+	pub prom: Vec<u8>,
+	pub crom: Vec<u8>,
+}
+
+impl fmt::Display for CartridgeError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			CartridgeError::BadMagic => {
+				write!(f, "not nes cartridge")
+			}
+			CartridgeError::Truncated { expected, actual } => {
+				write!(f, "truncated cartridge: expected {} bytes, got {}", expected, actual)
+			}
+			CartridgeError::UnsupportedMapper(no) => {
+				write!(f, "not supported mapper: {}", no)
+			}
+			CartridgeError::BadPrgSize(size) => {
+				write!(f, "bad PRG-ROM size for the mapper: {} bytes", size)
+			}
+			CartridgeError::BadChrSize(size) => {
+				write!(f, "bad CHR-ROM size for the mapper: {} bytes", size)
+			}
+			CartridgeError::Io(e) => {
+				write!(f, "cannot read cartridge: {}", e)
+			}
+		}
+	}
+}
+
+impl Error for CartridgeError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			CartridgeError::Io(e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for CartridgeError {
+	fn from(e: io::Error) -> CartridgeError {
+		return CartridgeError::Io(e);
+	}
+}
+
+impl RomHeader {
+	pub fn parse(data: &[u8]) -> Result<RomHeader, CartridgeError> {
+		if data.len() < HEADER_SIZE {
+			return Err(CartridgeError::Truncated { expected: HEADER_SIZE, actual: data.len() });
+		}
+		if data[0..4] != MAGIC {
+			return Err(CartridgeError::BadMagic);
+		}
+
+		let flag6 = data[6];
+		let flag7 = data[7];
+		let nes2 = flag7 & FLAG7_NES_2_0_MASK == FLAG7_NES_2_0;
+		let battery = flag6 & FLAG6_HAS_BATTARY_BACKUP != 0;
+
+		let mut header = RomHeader {
+			nes2: nes2,
+			mapper: ((flag6 & FLAG6_MAPPAER_LOW) >> 4) as u16,
+			submapper: 0,
+
+			prom_size: data[4] as usize * PROM_UNIT,
+			crom_size: data[5] as usize * CROM_UNIT,
+			prg_ram_size: 0,
+			prg_nvram_size: 0,
+			chr_ram_size: 0,
+			chr_nvram_size: 0,
+
+			vertical_mirror: flag6 & FLAG6_MIRROR != 0,
+			four_screen: flag6 & FLAG6_FOUR_SCREEN != 0,
+			battery: battery,
+			trainer: flag6 & FLAG6_HAS_TRAINER != 0,
+
+			timing: Timing::NTSC,
+			console_type: ConsoleType::NES,
+		};
+
+		if nes2 {
+			header.mapper |= (flag7 & FLAG7_MAPPER_HIGH) as u16;
+			header.mapper |= ((data[8] & BYTE8_MAPPER_MSB) as u16) << 8;
+			header.submapper = (data[8] & BYTE8_SUBMAPPER) >> 4;
+
+			header.prom_size = rom_size(data[4], data[9] & 0x0F, PROM_UNIT);
+			header.crom_size = rom_size(data[5], data[9] >> 4, CROM_UNIT);
+			header.prg_ram_size = ram_size(data[10] & 0x0F);
+			header.prg_nvram_size = ram_size(data[10] >> 4);
+			header.chr_ram_size = ram_size(data[11] & 0x0F);
+			header.chr_nvram_size = ram_size(data[11] >> 4);
+
+			header.timing = match data[12] & BYTE12_TIMING {
+				0 => Timing::NTSC,
+				1 => Timing::PAL,
+				2 => Timing::MULTI,
+				_ => Timing::DENDY,
+			};
+			header.console_type = match flag7 & FLAG7_CONSOLE_TYPE {
+				0 => ConsoleType::NES,
+				1 => ConsoleType::VS,
+				2 => ConsoleType::PLAYCHOICE,
+				_ => ConsoleType::EXTENDED(data[13] & 0x0F),
+			};
+		} else {
+			// Old dumps have garbage such as "DiskDude!" in bytes 7-15.
+			// Byte 7 can only be trusted when bytes 12-15 are clear.
+			if data[12..16].iter().all(|&b| b == 0) {
+				header.mapper |= (flag7 & FLAG7_MAPPER_HIGH) as u16;
+				header.console_type = match flag7 & FLAG7_CONSOLE_TYPE {
+					1 => ConsoleType::VS,
+					2 => ConsoleType::PLAYCHOICE,
+					_ => ConsoleType::NES,
+				};
+				if data[9] & FLAG9_PAL != 0 {
+					header.timing = Timing::PAL;
+				}
+			}
+
+			// 0 means 8k for compatibility
+			let prg_ram_size = if data[8] == 0 { 8 * 1024 } else { data[8] as usize * 8 * 1024 };
+			if battery {
+				header.prg_nvram_size = prg_ram_size;
+			} else {
+				header.prg_ram_size = prg_ram_size;
+			}
+			if header.crom_size == 0 {
+				header.chr_ram_size = 8 * 1024;
+			}
+		}
+
+		return Ok(header);
+	}
+
+	// Size of the whole file this header describes
+	pub fn file_size(&self) -> usize {
+		let trainer = if self.trainer { TRAINER_SIZE } else { 0 };
+		return (HEADER_SIZE + trainer).saturating_add(self.prom_size).saturating_add(self.crom_size);
+	}
+}
+
+impl Cartridge {
+	// CRC-32 of PRG-ROM and CHR-ROM, without the header
+	pub fn crc32(&self) -> u32 {
+		return crc32(&[&self.prom, &self.crom]);
+	}
+
+	pub fn parse(data: &[u8]) -> Result<Cartridge, CartridgeError> {
+		let header = RomHeader::parse(data)?;
+		if !is_supported_mapper(header.mapper) {
+			return Err(CartridgeError::UnsupportedMapper(header.mapper));
+		}
+		let (prg_bank_size, chr_bank_size) = mapper_bank_size(header.mapper);
+		if header.prom_size == 0 || header.prom_size % prg_bank_size != 0 {
+			return Err(CartridgeError::BadPrgSize(header.prom_size));
+		}
+		// 0 is CHR-RAM
+		if header.crom_size % chr_bank_size != 0 {
+			return Err(CartridgeError::BadChrSize(header.crom_size));
+		}
+		if data.len() < header.file_size() {
+			return Err(CartridgeError::Truncated { expected: header.file_size(), actual: data.len() });
+		}
+
+		let mut start = HEADER_SIZE;
+		let trainer = if header.trainer {
+			start += TRAINER_SIZE;
+			data[HEADER_SIZE .. start].to_vec()
+		} else {
+			Vec::new()
+		};
+		let prom = data[start .. start + header.prom_size].to_vec();
+		start += header.prom_size;
+		let crom = data[start .. start + header.crom_size].to_vec();
+
+		return Ok(Cartridge {
+			header: header,
+			trainer: trainer,
+			prom: prom,
+			crom: crom,
+		});
+	}
+}
+
+// NES 2.0 ROM size: LSB + MSB nibble, or exponent-multiplier when MSB nibble is $F
+fn rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+	if msb == 0x0F {
+		let exponent = (lsb >> 2) as u32;
+		let multiplier = (lsb & 0x03) as usize * 2 + 1;
+		return (1usize << exponent).saturating_mul(multiplier);
+	} else {
+		return (((msb as usize) << 8) | lsb as usize) * unit;
+	}
+}
+
+// NES 2.0 RAM size: 0 means none, otherwise 64 << shift
+fn ram_size(shift: u8) -> usize {
+	if shift == 0 {
+		return 0;
+	} else {
+		return 64 << shift;
+	}
+}
@@ -1,10 +1,13 @@
 use std::cell::RefCell;
 use std::rc::Rc; 
+use std::io;
 use crate::mmu::*;
+use crate::state::*;
+use crate::disasm::*;
 
-const NMI_VECTOR:   u16 = 0xFFFA;
-const RESET_VECTOR: u16 = 0xFFFC;
-const IRQ_VECTOR:   u16 = 0xFFFE;
+pub const NMI_VECTOR:   u16 = 0xFFFA;
+pub const RESET_VECTOR: u16 = 0xFFFC;
+pub const IRQ_VECTOR:   u16 = 0xFFFE;
 const BRK_VECTOR:   u16 = 0xFFFE;
 
 const FLG_C: u8 =  0x01;
@@ -29,7 +32,6 @@ macro_rules! SET_C { ($p:expr) =>{ $p |= FLG_C}}
 macro_rules! SET_Z { ($p:expr) =>{ $p |= FLG_Z}}
 macro_rules! SET_I { ($p:expr) =>{ $p |= FLG_I}}
 macro_rules! SET_D { ($p:expr) =>{ $p |= FLG_D}}
-macro_rules! SET_B { ($p:expr) =>{ $p |= FLG_B}}
 macro_rules! SET_5 { ($p:expr) =>{ $p |= FLG_5}}
 macro_rules! SET_V { ($p:expr) =>{ $p |= FLG_V}}
 macro_rules! SET_N { ($p:expr) =>{ $p |= FLG_N}}
@@ -37,7 +39,6 @@ macro_rules! UNSET_C { ($p:expr) =>{ $p &= IFLG_C}}
 macro_rules! UNSET_Z { ($p:expr) =>{ $p &= IFLG_Z}}
 macro_rules! UNSET_I { ($p:expr) =>{ $p &= IFLG_I}}
 macro_rules! UNSET_D { ($p:expr) =>{ $p &= IFLG_D}}
-macro_rules! UNSET_B { ($p:expr) =>{ $p &= IFLG_B}}
 macro_rules! UNSET_5 { ($p:expr) =>{ $p &= IFLG_5}}
 macro_rules! UNSET_V { ($p:expr) =>{ $p &= IFLG_V}}
 macro_rules! UNSET_N { ($p:expr) =>{ $p &= IFLG_N}}
@@ -46,25 +47,15 @@ macro_rules! UPDATE_Z { ($x: expr, $p: expr) => { if $x == 0 {SET_Z!($p)} else {
 macro_rules! UPDATE_N { ($x: expr, $p: expr) => { if ($x&0x80) !=  0 {SET_N!($p)} else {UNSET_N!($p)} } }
 macro_rules! UPDATE_NZ { ($x: expr, $p: expr) => { UPDATE_N!($x, $p); UPDATE_Z!($x, $p); } }
 
-const CLOCK_TABLE: [u8;256] = [
-        /* xx    00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F */
-        /*  0 */  1, 2, 0, 8, 3, 2, 2, 5, 1, 2, 1, 0, 4, 3, 3, 6,
-        /* 10 */  2, 2, 0, 8, 4, 2, 2, 6, 1, 3, 2, 7, 4, 3, 3, 7,
-        /* 20 */  3, 2, 0, 8, 2, 2, 2, 5, 1, 2, 1, 0, 3, 3, 3, 6,
-        /* 30 */  2, 2, 0, 8, 4, 2, 2, 6, 1, 3, 2, 7, 4, 3, 3, 7,
-        /* 40 */  1, 2, 0, 8, 3, 2, 2, 5, 1, 2, 1, 0, 3, 3, 3, 6,
-        /* 50 */  2, 2, 0, 8, 4, 2, 2, 6, 1, 3, 2, 7, 4, 3, 3, 7,
-        /* 60 */  1, 2, 0, 8, 3, 2, 2, 5, 1, 2, 1, 0, 3, 3, 3, 6,
-        /* 70 */  2, 2, 0, 8, 4, 2, 2, 6, 1, 3, 2, 7, 4, 3, 3, 7,
-        /* 80 */  2, 2, 2, 6, 2, 2, 2, 3, 1, 2, 1, 0, 3, 3, 3, 4,
-        /* 90 */  2, 2, 0, 0, 2, 2, 2, 4, 1, 3, 1, 0, 0, 3, 0, 0,
-        /* a0 */  2, 2, 2, 6, 2, 2, 2, 3, 1, 2, 1, 0, 3, 3, 3, 4,
-        /* b0 */  2, 2, 5, 0, 2, 2, 2, 4, 1, 3, 1, 0, 3, 3, 3, 4,
-        /* c0 */  2, 2, 2, 8, 2, 2, 2, 5, 1, 2, 1, 0, 3, 3, 3, 6,
-        /* d0 */  2, 2, 0, 8, 4, 2, 2, 6, 1, 3, 2, 7, 4, 3, 3, 7,
-        /* e0 */  2, 2, 2, 8, 2, 2, 2, 5, 1, 2, 1, 0, 3, 3, 3, 6,
-        /* f0 */  2, 2, 0, 8, 4, 2, 2, 6, 1, 3, 2, 7, 4, 3, 3, 7,
-];
+#[derive(Clone, Copy)]
+pub struct Registers {
+	pub a: u8,
+	pub x: u8,
+	pub y: u8,
+	pub sp: u8,
+	pub p: u8,
+	pub pc: u16,
+}
 
 pub struct CPU {
 	a: u8,
@@ -75,10 +66,16 @@ pub struct CPU {
 
 	pc: u16,
 
-	clock_remain: u32,
+	cycles: u64,
 	reset_flag: bool,
-	nmi_flag: bool,
-	irq_flag: bool,
+
+	// Interrupt polling, see poll_interrupts()
+	nmi_line: bool,         // NMI line in the previous cycle, for edge detection
+	nmi_pending: bool,      // edge latched, cleared when the NMI is taken
+	irq_pending: bool,      // IRQ line asserted and I clear
+	prev_nmi_pending: bool, // ... as of the previous cycle
+	prev_irq_pending: bool,
+	interrupt: bool,        // take an interrupt before the next instruction
 
 	mmu: Rc<RefCell<MMU>>,
 }
@@ -89,50 +86,135 @@ impl CPU {
 			a: 0,
 			x: 0,
 			y: 0,
-			sp: 0xFD,
+			sp: 0x00, // 0xFD after the reset sequence
 			p: 0x24,
 			pc: 0,
-			clock_remain: 0,
+			cycles: 0,
 			reset_flag: false,
-			nmi_flag: false,
-			irq_flag: false,
+			nmi_line: false,
+			nmi_pending: false,
+			irq_pending: false,
+			prev_nmi_pending: false,
+			prev_irq_pending: false,
+			interrupt: false,
 			mmu: mmu 
 		}
 	}
 
+	// Runs one instruction, or the reset/NMI/IRQ sequence
 	pub fn clock(&mut self) {
-		if self.reset_flag {
-			self.do_reset();
+		let mmu = Rc::clone(&self.mmu);
+		let mut mmu = mmu.borrow_mut();
+
+		// Bus access
+		//   Every CPU cycle is exactly one read or write. The PPU and APU are
+		//   caught up by one CPU cycle (MMU::tick) before each access, so the
+		//   access lands on its real cycle and cycle counts follow from the
+		//   accesses an instruction makes.
+		macro_rules! READ {
+			($addr: expr) => {{
+				let addr: u16 = $addr;
+				self.cycles += 1;
+				mmu.tick();
+				let v = mmu.read_1byte(addr);
+				self.poll_interrupts(&mmu);
+				v
+			}};
 		}
-		if self.nmi_flag {
-			self.do_nmi();
+		macro_rules! DUMMY_READ {
+			($addr: expr) => {
+				let addr: u16 = $addr;
+				self.cycles += 1;
+				mmu.tick();
+				mmu.read_1byte(addr);
+				self.poll_interrupts(&mmu);
+			};
 		}
-		if self.irq_flag {
-			self.do_irq();
+		macro_rules! WRITE {
+			($addr: expr, $v: expr) => {
+				let addr: u16 = $addr;
+				let v: u8 = $v;
+				self.cycles += 1;
+				mmu.tick();
+				mmu.write(addr, v);
+				self.poll_interrupts(&mmu);
+			};
 		}
-	
-		if self.clock_remain > 0 {
-			self.clock_remain -= 1;
-			return;
+		// Read-modify-write: the unmodified value is written back while the ALU works
+		macro_rules! RMW_READ {
+			($ea: expr) => {{
+				let m = READ!($ea);
+				WRITE!($ea, m);
+				m
+			}};
 		}
 
-		let mut mmu = self.mmu.borrow_mut();
-
 		// PUSH/POP
 		macro_rules! PUSH {
 			($v: expr) => {
-				mmu.write(0x0100 + (self.sp as u16), $v);
-				self.sp -= 1;
+				WRITE!(0x0100 + (self.sp as u16), $v);
+				self.sp = self.sp.wrapping_sub(1);
 			}
 		}
 		macro_rules! POP {
 			() => {{
-				self.sp += 1;
-				mmu.read_1byte(0x0100 + (self.sp as u16))
+				self.sp = self.sp.wrapping_add(1);
+				READ!(0x0100 + (self.sp as u16))
 			}};
 		}
 
+		// NMI/IRQ/BRK push PC, then P, and jump through a vector.
+		// The vector is chosen after PC has been pushed, so an NMI that
+		// arrives by then hijacks an IRQ or BRK.
+		macro_rules! INTERRUPT {
+			($vector: expr, $b: expr) => {
+				PUSH!((self.pc >> 8) as u8);
+				PUSH!(self.pc as u8);
+				let vector = if self.nmi_pending {
+					self.nmi_pending = false;
+					NMI_VECTOR
+				} else {
+					$vector
+				};
+				PUSH!(self.p | $b);
+				SET_I!(self.p);
+				let lo = READ!(vector) as u16;
+				let hi = READ!(vector + 1) as u16;
+				self.pc = (hi << 8) | lo;
+			}
+		}
+
+		if self.reset_flag {
+			// Same sequence as an interrupt, but the stack writes are turned into reads
+			println!("cpu:reset");
+			DUMMY_READ!(self.pc);
+			DUMMY_READ!(self.pc);
+			for _ in 0..3 {
+				DUMMY_READ!(0x0100 + (self.sp as u16));
+				self.sp = self.sp.wrapping_sub(1);
+			}
+			SET_I!(self.p);
+			let lo = READ!(RESET_VECTOR) as u16;
+			let hi = READ!(RESET_VECTOR + 1) as u16;
+			self.pc = (hi << 8) | lo;
+			self.reset_flag = false;
+			self.nmi_pending = false;
+			self.interrupt = false;
+			return;
+		}
+		if self.interrupt {
+			// 7 cycles, starting with two reads of PC in place of the opcode
+			// fetch. The first instruction of the handler always runs.
+			self.interrupt = false;
+			DUMMY_READ!(self.pc);
+			DUMMY_READ!(self.pc);
+			INTERRUPT!(IRQ_VECTOR, 0);
+			return;
+		}
+
 		// Addressing modes
+		//   Operand fetches and dummy reads happen here, the access to the
+		//   effective address is done by the instruction.
 		let mut ea: u16;
 		macro_rules! IMM { 
 			($ea: expr, $pc: expr) => {
@@ -142,27 +224,46 @@ impl CPU {
 		}
 		macro_rules! ABS {
 			($ea: expr, $pc: expr) => {
-				$ea = mmu.read_2bytes(self.pc);
+				let lo = READ!(self.pc) as u16;
+				let hi = READ!(self.pc + 1) as u16;
+				$ea = (hi << 8) | lo;
 				$pc = self.pc + 2;
 			}
 		}
+		// Reads take an extra cycle only when indexing crosses a page
 		macro_rules! ABS_INDEXED {
 			($ea: expr, $pc: expr, $i: expr) => {
-				let m = mmu.read_2bytes(self.pc);
-				let m: u16 = m.wrapping_add($i as u16);
-				$ea = m;
+				let lo = READ!(self.pc) as u16;
+				let hi = READ!(self.pc + 1) as u16;
+				let m: u16 = (hi << 8) | lo;
+				$ea = m.wrapping_add($i as u16);
+				if (m ^ $ea) & 0xFF00 != 0 {
+					DUMMY_READ!((m & 0xFF00) | ($ea & 0x00FF));
+				}
+				$pc = self.pc + 2;
+			}
+		}
+		// Writes and read-modify-writes always take it
+		macro_rules! ABS_INDEXED_W {
+			($ea: expr, $pc: expr, $i: expr) => {
+				let lo = READ!(self.pc) as u16;
+				let hi = READ!(self.pc + 1) as u16;
+				let m: u16 = (hi << 8) | lo;
+				$ea = m.wrapping_add($i as u16);
+				DUMMY_READ!((m & 0xFF00) | ($ea & 0x00FF));
 				$pc = self.pc + 2;
 			}
 		}
 		macro_rules! ZERO_PAGE {
 			($ea: expr, $pc: expr) => {
-				$ea = mmu.read_1byte(self.pc) as u16;
+				$ea = READ!(self.pc) as u16;
 				$pc = self.pc + 1;
 			}
 		}
 		macro_rules! ZERO_PAGE_INDEXED {
 			($ea: expr, $pc: expr, $i: expr) => {
-				$ea = mmu.read_1byte(self.pc) as u16;
+				$ea = READ!(self.pc) as u16;
+				DUMMY_READ!($ea);
 				$ea = $ea.wrapping_add($i as u16);
 				$ea &= 0x00FFu16;
 				$pc = self.pc + 1;
@@ -170,85 +271,120 @@ impl CPU {
 		}
 		macro_rules! REL {
 			($ea: expr, $pc: expr) => {
-				let m:i8 = mmu.read_1byte($pc) as i8;
+				let m:i8 = READ!($pc) as i8;
 				$pc += 1;
 				$ea = $pc.wrapping_add(m as u16);
 			}
 		}
+		// JMP ($xxFF) fetches the high byte from $xx00
 		macro_rules! INDIRECT {
 			($ea: expr, $pc: expr) => {
-				$ea = mmu.indirect(self.pc);
+				let lo = READ!(self.pc) as u16;
+				let hi = READ!(self.pc + 1) as u16;
+				let m: u16 = (hi << 8) | lo;
+				let lo = READ!(m) as u16;
+				let hi = READ!((m & 0xFF00) | (m.wrapping_add(1) & 0x00FF)) as u16;
+				$ea = (hi << 8) | lo;
 				$pc += 2;
 			}
 		}
 		macro_rules! INDIRECT_X {
 			($ea: expr, $pc: expr) => {
-				$ea = mmu.indirect_x(self.pc, self.x);
+				let z: u8 = READ!(self.pc);
+				DUMMY_READ!(z as u16);
+				let z: u8 = z.wrapping_add(self.x);
+				let lo = READ!(z as u16) as u16;
+				let hi = READ!(z.wrapping_add(1) as u16) as u16;
+				$ea = (hi << 8) | lo;
 				$pc += 1;
 			}
 		}
 		macro_rules! INDIRECT_Y {
 			($ea: expr, $pc: expr) => {
-				$ea = mmu.indirect_y(self.pc, self.y);
+				let z: u8 = READ!(self.pc);
+				let lo = READ!(z as u16) as u16;
+				let hi = READ!(z.wrapping_add(1) as u16) as u16;
+				let m: u16 = (hi << 8) | lo;
+				$ea = m.wrapping_add(self.y as u16);
+				if (m ^ $ea) & 0xFF00 != 0 {
+					DUMMY_READ!((m & 0xFF00) | ($ea & 0x00FF));
+				}
+				$pc += 1;
+			}
+		}
+		macro_rules! INDIRECT_Y_W {
+			($ea: expr, $pc: expr) => {
+				let z: u8 = READ!(self.pc);
+				let lo = READ!(z as u16) as u16;
+				let hi = READ!(z.wrapping_add(1) as u16) as u16;
+				let m: u16 = (hi << 8) | lo;
+				$ea = m.wrapping_add(self.y as u16);
+				DUMMY_READ!((m & 0xFF00) | ($ea & 0x00FF));
 				$pc += 1;
 			}
 		}
 
 		// Opcode
-		macro_rules! BCC {
-			($ea: expr) => {
-				if self.p&FLG_C == 0 {
+		// A taken branch adds a cycle, and one more if it crosses a page
+		macro_rules! BRANCH {
+			($cond: expr, $ea: expr) => {
+				if $cond {
+					// A taken branch doesn't poll on its extra cycle, so an IRQ
+					// or NMI arriving then waits until after the next instruction.
+					// The NMI edge stays latched, only this poll is skipped.
+					if self.irq_pending && !self.prev_irq_pending {
+						self.irq_pending = false;
+					}
+					let delay_nmi = self.nmi_pending && !self.prev_nmi_pending;
+					DUMMY_READ!(self.pc);
+					if delay_nmi {
+						self.prev_nmi_pending = false;
+					}
+					if (self.pc ^ $ea) & 0xFF00 != 0 {
+						DUMMY_READ!((self.pc & 0xFF00) | ($ea & 0x00FF));
+					}
 					self.pc = $ea;
 				}
 			};
 		}
+		macro_rules! BCC {
+			($ea: expr) => {
+				BRANCH!(self.p&FLG_C == 0, $ea);
+			};
+		}
 		macro_rules! BCS {
 			($ea: expr) => {
-				if self.p&FLG_C != 0 {
-					self.pc = $ea;
-				}
+				BRANCH!(self.p&FLG_C != 0, $ea);
 			};
 		}
 		macro_rules! BEQ {
 			($ea: expr) => {
-				if self.p&FLG_Z != 0 {
-					self.pc = $ea;
-				}
+				BRANCH!(self.p&FLG_Z != 0, $ea);
 			};
 		}
 		macro_rules! BVC {
 			($ea: expr) => {
-				if self.p&FLG_V == 0 {
-					self.pc = $ea;
-				}
+				BRANCH!(self.p&FLG_V == 0, $ea);
 			}
 		}
 		macro_rules! BVS {
 			($ea: expr) => {
-				if self.p&FLG_V != 0 {
-					self.pc = $ea;
-				}
+				BRANCH!(self.p&FLG_V != 0, $ea);
 			}
 		}
 		macro_rules! BPL {
 			($ea:expr) => {
-				if self.p&FLG_N == 0 {
-					self.pc = $ea;
-				}
+				BRANCH!(self.p&FLG_N == 0, $ea);
 			}
 		}
 		macro_rules! BMI {
 			($ea:expr) => {
-				if self.p&FLG_N != 0 {
-					self.pc = $ea;
-				}
+				BRANCH!(self.p&FLG_N != 0, $ea);
 			}
 		}
 		macro_rules! BNE {
 			($ea:expr) => {
-				if self.p&FLG_Z == 0 {
-					self.pc = $ea;
-				}
+				BRANCH!(self.p&FLG_Z == 0, $ea);
 			}
 		}
 		macro_rules! JMP {
@@ -256,51 +392,56 @@ impl CPU {
 				self.pc = $ea;
 			}
 		}
+		// The high byte of the target is fetched after the return address is pushed
 		macro_rules! JSR {
-			($ea:expr) => {
-				mmu.push_2bytes(0x0100 + self.sp as u16, self.pc -1);
-				self.sp -= 2;
-				self.pc = $ea;
+			() => {
+				let lo = READ!(self.pc) as u16;
+				self.pc += 1;
+				DUMMY_READ!(0x0100 + self.sp as u16);
+				PUSH!((self.pc >> 8) as u8);
+				PUSH!(self.pc as u8);
+				let hi = READ!(self.pc) as u16;
+				self.pc = (hi << 8) | lo;
 			}
 		}
 		macro_rules! LDA {
 			($ea:expr) => {
-				self.a = mmu.read_1byte($ea);
+				self.a = READ!($ea);
 				UPDATE_NZ!(self.a, self.p);
 			}
 		}
 		macro_rules! LDX {
 			($ea:expr) => {
-				self.x = mmu.read_1byte($ea);
+				self.x = READ!($ea);
 				UPDATE_NZ!(self.x, self.p);
 			}
 		}
 		macro_rules! LDY {
 			($ea:expr) => {
-				self.y = mmu.read_1byte($ea);
+				self.y = READ!($ea);
 				UPDATE_NZ!(self.y, self.p);
 			}
 		}
 		macro_rules! LAX {
 			($ea:expr) => {
-				self.x = mmu.read_1byte($ea);
-				self.a = self.a;
+				self.x = READ!($ea);
+				self.a = self.x;
 				UPDATE_NZ!(self.a, self.p);
 			}
 		}
 		macro_rules! STA {
 			($ea: expr) => {
-				mmu.write($ea, self.a);
+				WRITE!($ea, self.a);
 			}
 		}
 		macro_rules! STX {
 			($ea: expr) => {
-				mmu.write($ea, self.x);
+				WRITE!($ea, self.x);
 			}
 		}
 		macro_rules! STY {
 			($ea: expr) => {
-				mmu.write($ea, self.y);
+				WRITE!($ea, self.y);
 			}
 		}
 		macro_rules! INX {
@@ -329,17 +470,21 @@ impl CPU {
 		}
 		macro_rules! RTS {
 			() => {
-				self.pc = mmu.pop_2bytes(0x0100 + self.sp as u16);
+				DUMMY_READ!(0x0100 + self.sp as u16);
+				let lo = POP!() as u16;
+				let hi = POP!() as u16;
+				self.pc = (hi << 8) | lo;
+				DUMMY_READ!(self.pc);
 				self.pc += 1;
-				self.sp += 2;
 			}
 		}
 		macro_rules! RTI {
 			() => {
-				self.sp = self.sp +1;
-				self.p = mmu.read_1byte(0x0100 + self.sp as u16);
-				self.pc = mmu.pop_2bytes(0x0100 + self.sp as u16);
-				self.sp += 2;
+				DUMMY_READ!(0x0100 + self.sp as u16);
+				self.p = POP!() & IFLG_B;
+				let lo = POP!() as u16;
+				let hi = POP!() as u16;
+				self.pc = (hi << 8) | lo;
 			}
 		}
 		macro_rules! TAX {
@@ -415,19 +560,19 @@ impl CPU {
 		}
 		macro_rules! AND {
 			($ea: expr) => {
-				self. a &= mmu.read_1byte($ea);
+				self. a &= READ!($ea);
 				UPDATE_NZ!(self.a, self.p);
 			};
 		}
 		macro_rules! ORA {
 			($ea: expr) => {
-				self.a |= mmu.read_1byte($ea);
+				self.a |= READ!($ea);
 				UPDATE_NZ!(self.a, self.p);
 			}
 		}
 		macro_rules! EOR {
 			($ea: expr) => {
-				let m = mmu.read_1byte($ea);
+				let m = READ!($ea);
 				self.a ^= m;
 				UPDATE_NZ!(self.a, self.p);
 			}
@@ -435,7 +580,7 @@ impl CPU {
 		macro_rules! AAX {
 			($ea: expr) => {
 				let m: u8 = self.x & self.a;
-				mmu.write($ea, m);
+				WRITE!($ea, m);
 				UPDATE_NZ!(m, self.p);
 			}
 		}
@@ -452,14 +597,14 @@ impl CPU {
 		}
 		macro_rules! LSR {
 			($ea: expr) => {
-				let m = mmu.read_1byte($ea);
+				let m = RMW_READ!($ea);
 				if m & 0x01 == 0 {
 					UNSET_C!(self.p);
 				} else {
 					SET_C!(self.p);
 				}
 				let m = m >> 1;
-				mmu.write($ea, m);
+				WRITE!($ea, m);
 				UPDATE_NZ!(m, self.p);
 			}
 		}
@@ -476,14 +621,14 @@ impl CPU {
 		}
 		macro_rules! ASL {
 			($ea: expr) => {
-				let m = mmu.read_1byte($ea);
+				let m = RMW_READ!($ea);
 				if m & 0x80 == 0 {
 					UNSET_C!(self.p);
 				} else {
 					SET_C!(self.p);
 				}
 				let m = m << 1;
-				mmu.write($ea, m);
+				WRITE!($ea, m);
 				UPDATE_NZ!(m, self.p);
 			}
 		}
@@ -506,7 +651,7 @@ impl CPU {
 		}
 		macro_rules! ROL {
 			($ea: expr) => {
-				let m = mmu.read_1byte($ea);
+				let m = RMW_READ!($ea);
 				let mut mm = m;
 				mm = m << 1;
 				mm |= if (self.p & FLG_C) != 0 {
@@ -519,7 +664,7 @@ impl CPU {
 				} else {
 					UNSET_C!(self.p);
 				}
-				mmu.write($ea, mm);
+				WRITE!($ea, mm);
 				UPDATE_NZ!(mm, self.p);
 			}
 		}
@@ -542,7 +687,7 @@ impl CPU {
 		}
 		macro_rules! ROR {
 			($ea: expr) => {
-				let m = mmu.read_1byte($ea);
+				let m = RMW_READ!($ea);
 				let mut mm = m;
 				mm = m >> 1;
 				mm |= if (self.p & FLG_C) != 0 {
@@ -555,42 +700,42 @@ impl CPU {
 				} else {
 					UNSET_C!(self.p);
 				}
-				mmu.write($ea, mm);
+				WRITE!($ea, mm);
 				UPDATE_NZ!(mm, self.p);
 			}
 		}
 		macro_rules! INC {
 			($ea: expr) => {
-				let m:u8 = mmu.read_1byte($ea);
+				let m:u8 = RMW_READ!($ea);
 				let m = m.wrapping_add(1);
-				mmu.write($ea, m);
+				WRITE!($ea, m);
 				UPDATE_NZ!(m, self.p);
 			}
 		}
 		macro_rules! DEC {
 			($ea: expr) => {
-				let mut m:u8 = mmu.read_1byte($ea);
+				let mut m:u8 = RMW_READ!($ea);
 				m = m.wrapping_sub(1);
-				mmu.write($ea, m);
+				WRITE!($ea, m);
 				UPDATE_NZ!(m, self.p);
 			}
 		}
 		macro_rules! DCP {
 			($ea: expr) => {
-				let mut m:u8 = mmu.read_1byte($ea);
+				let mut m:u8 = RMW_READ!($ea);
 				m = m.wrapping_sub(1);
 				if self.a >= m {
 					SET_C!(self.p);
 				} else {
 					UNSET_C!(self.p);
 				}
-				mmu.write($ea, m);
+				WRITE!($ea, m);
 				UPDATE_NZ!(self.a.wrapping_sub(m), self.p);
 			}
 		}
 		macro_rules! ADC {
 			($ea: expr) => {
-				let m:u8 = mmu.read_1byte($ea);
+				let m:u8 = READ!($ea);
 				let c:u8 = if self.p & FLG_C != 0 {1} else {0};
 				let t:u16 = self.a as u16 + m as u16 + c as u16;
 
@@ -612,7 +757,7 @@ impl CPU {
 		}
 		macro_rules! SBC {
 			($ea: expr) => {
-				let m:u8 = mmu.read_1byte($ea);
+				let m:u8 = READ!($ea);
 				let c:u8 = if self.p & FLG_C != 0 {0} else {1};
 
 				let t:u16 = 0x100u16 + self.a as u16 - m as u16 - c as u16;
@@ -634,16 +779,17 @@ impl CPU {
 		}
 		macro_rules! ISC {
 			($ea: expr) => {
-				let m: u8 = mmu.read_1byte($ea);
+				let m: u8 = RMW_READ!($ea);
 				let m: u8 = m.wrapping_add(1);
-				let c:u8 = if self.p & FLG_C != 0 {1} else {0};
-				if self.a >= m {
+				WRITE!($ea, m);
+				let c:u8 = if self.p & FLG_C != 0 {0} else {1};
+				let t:u16 = 0x100u16 + self.a as u16 - m as u16 - c as u16;
+				if t >= 0x100 {
 					SET_C!(self.p);
 				} else {
 					UNSET_C!(self.p);
 				}
-				let new_a:u8 = self.a.wrapping_sub(m);
-				let new_a:u8 = new_a.wrapping_sub(c);
+				let new_a:u8 = (t&0x00FFu16) as u8;
 				if ((self.a ^ m) & (self.a ^ new_a) & 0x80) == 0x80 {
 					SET_V!(self.p);
 				} else {
@@ -655,7 +801,7 @@ impl CPU {
 		}
 		macro_rules! CMP {
 			($ea: expr) => {
-				let m:u8 = mmu.read_1byte(ea);
+				let m:u8 = READ!(ea);
 				if self.a >= m {
 					SET_C!(self.p);
 				} else {
@@ -666,7 +812,7 @@ impl CPU {
 		}
 		macro_rules! CPX {
 			($ea: expr) => {
-				let m:u8 = mmu.read_1byte(ea);
+				let m:u8 = READ!(ea);
 				if self.x >= m {
 					SET_C!(self.p);
 				} else {
@@ -677,7 +823,7 @@ impl CPU {
 		}
 		macro_rules! CPY {
 			($ea: expr) => {
-				let m:u8 = mmu.read_1byte(ea);
+				let m:u8 = READ!(ea);
 				if self.y >= m {
 					SET_C!(self.p);
 				} else {
@@ -688,7 +834,7 @@ impl CPU {
 		}
 		macro_rules! BIT {
 			($ea: expr) => {
-				let mut m:u8 = mmu.read_1byte($ea);
+				let mut m:u8 = READ!($ea);
 				self.p = (m&0xC0)|(self.p&0x3F);
 				m &= self.a;
 				UPDATE_Z!(m, self.p);
@@ -701,6 +847,7 @@ impl CPU {
 		}
 		macro_rules! PLA {
 			() => {
+				DUMMY_READ!(0x0100 + self.sp as u16);
 				self.a = POP!();
 				UPDATE_NZ!(self.a, self.p);
 			}
@@ -712,40 +859,41 @@ impl CPU {
 		}
 		macro_rules! PLP {
 			() => {
-				self.p = POP!();
+				DUMMY_READ!(0x0100 + self.sp as u16);
+				self.p = POP!() & IFLG_B;
 			}
 		}
 		macro_rules! SLO {
 			($ea: expr) => {
-				let m: u8 = mmu.read_1byte($ea);
+				let m: u8 = RMW_READ!($ea);
 				if m & 0x80 != 0 {
 					SET_C!(self.p);
 				} else {
 					UNSET_C!(self.p);
 				}
 				let m: u8 = m << 1;
+				WRITE!($ea, m);
 				self.a |= m;
 				UPDATE_NZ!(self.a, self.p);
 			}
 		}
 		macro_rules! SRE {
 			($ea: expr) => {
-				let m: u8 = mmu.read_1byte($ea);
-				let c = if self.p & FLG_C == 0 {0x09} else {0x80};
+				let m: u8 = RMW_READ!($ea);
 				if m & 0x01 != 0 {
 					SET_C!(self.p);
 				} else {
 					UNSET_C!(self.p);
 				}
 				let m: u8 = m >> 1;
-				let m: u8 = m | c;
+				WRITE!($ea, m);
 				self.a ^= m;
 				UPDATE_NZ!(self.a, self.p);
 			}
 		}
 		macro_rules! RLA {
 			($ea: expr) => {
-				let m: u8 = mmu.read_1byte($ea);
+				let m: u8 = RMW_READ!($ea);
 				let c = if self.p & FLG_C == 0 {0x0} else {0x1};
 				if m & 0x80 != 0 {
 					SET_C!(self.p);
@@ -754,13 +902,14 @@ impl CPU {
 				}
 				let m:u8 = m << 1;
 				let m:u8 = m|c;
+				WRITE!($ea, m);
 				self.a &= m;
 				UPDATE_NZ!(self.a, self.p);
 			}
 		}
 		macro_rules! RRA {
 			($ea: expr) => {
-				let m: u8 = mmu.read_1byte($ea);
+				let m: u8 = RMW_READ!($ea);
 				let c = if self.p & FLG_C == 0 {0x00} else {0x80};
 				if m & 0x01 != 0 {
 					SET_C!(self.p);
@@ -769,7 +918,7 @@ impl CPU {
 				}
 				let m:u8 = m >> 1;
 				let m:u8 = m|c;
-				mmu.write($ea, m);
+				WRITE!($ea, m);
 
 				let c = if self.p & FLG_C == 0 {0} else {1};
 				let t:u16 = self.a as u16 + m as u16 + c as u16;
@@ -790,21 +939,25 @@ impl CPU {
 		}
 		macro_rules! BRK {
 			() => {
-				if (self.p & FLG_I) == 0 {
-					SET_B!(self.p);
-					self.pc += 1;
-					mmu.push_2bytes(0x0100 + self.sp as u16, self.pc);
-					PUSH!(self.p);
-					SET_I!(self.p);
-					self.pc = mmu.read_2bytes(BRK_VECTOR);
-				}
+				// the byte after BRK is skipped, B is set only in the pushed copy of P
+				self.pc += 1;
+				INTERRUPT!(BRK_VECTOR, FLG_B);
+				self.prev_nmi_pending = false;
 			}
 		}
 
 		// read opcode
-		let op:u8 = mmu.read_1byte(self.pc);
+		let op:u8 = READ!(self.pc);
 		self.pc += 1;
 
+		// One-byte instructions still read the next byte on their second cycle
+		match OPCODES[op as usize].mode {
+			Mode::IMP | Mode::ACC => {
+				DUMMY_READ!(self.pc);
+			}
+			_ => {}
+		}
+
 		match op {
 			0x00 => { // BRK
 				BRK!();
@@ -819,7 +972,7 @@ impl CPU {
 			}
 			0x04 => { // NOP ZeroPage (Undocumented)
 				ZERO_PAGE!(ea, self.pc);
-				// NOP
+				DUMMY_READ!(ea);
 			}
 			0x05 => { // ORA ZeroPage
 				ZERO_PAGE!(ea, self.pc);
@@ -845,7 +998,7 @@ impl CPU {
 			}
 			0x0C => { // NOP Absolute (Undocumented)
 				ABS!(ea, self.pc);
-				// NOP
+				DUMMY_READ!(ea);
 			}
 			0x0D => { // ORA Absolute
 				ABS!(ea, self.pc);
@@ -868,12 +1021,12 @@ impl CPU {
 				ORA!(ea);
 			}
 			0x13 => { // SLO Indirect, Y (undocumented)
-				INDIRECT_Y!(ea, self.pc);
+				INDIRECT_Y_W!(ea, self.pc);
 				SLO!(ea);
 			}
 			0x14 => { // NOP ZeroPage, X (Undocumented)
 				ZERO_PAGE_INDEXED!(ea, self.pc, self.x);
-				// NOP
+				DUMMY_READ!(ea);
 			}
 			0x15 => { // ORA ZeroPage, X
 				ZERO_PAGE_INDEXED!(ea, self.pc, self.x);
@@ -897,27 +1050,27 @@ impl CPU {
 			0x1A => { // NOP (undocumented)
 			}
 			0x1B => { // SLO Absolute, Y (undocumented)
-				ABS_INDEXED!(ea, self.pc, self.y);
+				ABS_INDEXED_W!(ea, self.pc, self.y);
 				SLO!(ea);
 			}
 			0x1C => { // NOP Absolute, X (undocumented)
 				ABS_INDEXED!(ea, self.pc, self.x);
+				DUMMY_READ!(ea);
 			}
 			0x1D => { // ORA Absolute, X
 				ABS_INDEXED!(ea, self.pc, self.x);
 				ORA!(ea);
 			}
 			0x1E => { // ASL Absolute, X
-				ABS_INDEXED!(ea, self.pc, self.x);
+				ABS_INDEXED_W!(ea, self.pc, self.x);
 				ASL!(ea);
 			}
 			0x1F => { // SLO Absolute, X (undocumented)
-				ABS_INDEXED!(ea, self.pc, self.x);
+				ABS_INDEXED_W!(ea, self.pc, self.x);
 				SLO!(ea);
 			}
 			0x20 => { // JSR Absolute
-				ABS!(ea, self.pc);
-				JSR!(ea);
+				JSR!();
 			}
 			0x21 => { // AND Indirect, X
 				INDIRECT_X!(ea, self.pc);
@@ -978,12 +1131,12 @@ impl CPU {
 				AND!(ea);
 			}
 			0x33 => { // RLA Indirect, Y
-				INDIRECT_Y!(ea, self.pc);
+				INDIRECT_Y_W!(ea, self.pc);
 				RLA!(ea);
 			}
 			0x34 => { // NOP ZeroPage, X (Undocumented)
 				ZERO_PAGE_INDEXED!(ea, self.pc, self.x);
-				// NOP
+				DUMMY_READ!(ea);
 			}
 			0x35 => { // AND ZeroPage, X
 				ZERO_PAGE_INDEXED!(ea, self.pc, self.x);
@@ -1007,22 +1160,23 @@ impl CPU {
 			0x3A => { // NOP (undocumented)
 			}
 			0x3B => { // RLA Absolute, Y
-				ABS_INDEXED!(ea, self.pc, self.y);
+				ABS_INDEXED_W!(ea, self.pc, self.y);
 				RLA!(ea);
 			}
 			0x3C => { // NOP Absolute, X (undocumented)
 				ABS_INDEXED!(ea, self.pc, self.x);
+				DUMMY_READ!(ea);
 			}
 			0x3D => { // AND Absolute, X
 				ABS_INDEXED!(ea, self.pc, self.x);
 				AND!(ea);
 			}
 			0x3E => { // ROL Absolute, X
-				ABS_INDEXED!(ea, self.pc, self.x);
+				ABS_INDEXED_W!(ea, self.pc, self.x);
 				ROL!(ea);
 			}
 			0x3F => { // RLA Absolute, X
-				ABS_INDEXED!(ea, self.pc, self.x);
+				ABS_INDEXED_W!(ea, self.pc, self.x);
 				RLA!(ea);
 			}
 			0x40 => { // RTI
@@ -1038,7 +1192,7 @@ impl CPU {
 			}
 			0x44 => { // NOP ZeroPage (Undocumented)
 				ZERO_PAGE!(ea, self.pc);
-				// NOP
+				DUMMY_READ!(ea);
 			}
 			0x45 => { // EOR ZeroPage
 				ZERO_PAGE!(ea, self.pc);
@@ -1087,12 +1241,12 @@ impl CPU {
 				EOR!(ea);
 			}
 			0x53 => { // SRE Indirect, Y (undocumented)
-				INDIRECT_Y!(ea, self.pc);
+				INDIRECT_Y_W!(ea, self.pc);
 				SRE!(ea);
 			}
 			0x54 => { // NOP ZeroPage, X (Undocumented)
 				ZERO_PAGE_INDEXED!(ea, self.pc, self.x);
-				// NOP
+				DUMMY_READ!(ea);
 			}
 			0x55 => { // EOR ZeroPage, X
 				ZERO_PAGE_INDEXED!(ea, self.pc, self.x);
@@ -1116,22 +1270,23 @@ impl CPU {
 			0x5A => { // NOP (undocumented)
 			}
 			0x5B => { // SRE Absolute, Y (undocumented)
-				ABS_INDEXED!(ea, self.pc, self.y);
+				ABS_INDEXED_W!(ea, self.pc, self.y);
 				SRE!(ea);
 			}
 			0x5C => { // NOP Absolute, X (undocumented)
 				ABS_INDEXED!(ea, self.pc, self.x);
+				DUMMY_READ!(ea);
 			}
 			0x5D => { // EOR Absolute, X
 				ABS_INDEXED!(ea, self.pc, self.x);
 				EOR!(ea);
 			}
 			0x5E => { // LSR Absolute, X
-				ABS_INDEXED!(ea, self.pc, self.x);
+				ABS_INDEXED_W!(ea, self.pc, self.x);
 				LSR!(ea);
 			}
 			0x5F => { // SRE Absolute, X (undocumented)
-				ABS_INDEXED!(ea, self.pc, self.x);
+				ABS_INDEXED_W!(ea, self.pc, self.x);
 				SRE!(ea);
 			}
 			0x60 => { // RTS
@@ -1147,7 +1302,7 @@ impl CPU {
 			}
 			0x64 => { // NOP ZeroPage (Undocumented)
 				ZERO_PAGE!(ea, self.pc);
-				// NOP
+				DUMMY_READ!(ea);
 			}
 			0x65 => { // ADC ZeroPage
 				ZERO_PAGE!(ea, self.pc);
@@ -1196,12 +1351,12 @@ impl CPU {
 				ADC!(ea);
 			}
 			0x73 => { // RRA Indirect, Y (undocumented)
-				INDIRECT_Y!(ea, self.pc);
+				INDIRECT_Y_W!(ea, self.pc);
 				RRA!(ea);
 			}
 			0x74 => { // NOP ZeroPage, X (Undocumented)
 				ZERO_PAGE_INDEXED!(ea, self.pc, self.x);
-				// NOP
+				DUMMY_READ!(ea);
 			}
 			0x75 => { // ADC ZeroPage, X
 				ZERO_PAGE_INDEXED!(ea, self.pc, self.x);
@@ -1225,26 +1380,28 @@ impl CPU {
 			0x7A => { // NOP (undocumented)
 			}
 			0x7B => { // RRA Absolute, Y (undocumented)
-				ABS_INDEXED!(ea, self.pc, self.y);
+				ABS_INDEXED_W!(ea, self.pc, self.y);
 				RRA!(ea);
 			}
 			0x7C => { // NOP Absolute, X (undocumented)
 				ABS_INDEXED!(ea, self.pc, self.x);
+				DUMMY_READ!(ea);
 			}
 			0x7D => { // ADC Absolute, X
 				ABS_INDEXED!(ea, self.pc, self.x);
 				ADC!(ea);
 			}
 			0x7E => { // ROR Absolute, X
-				ABS_INDEXED!(ea, self.pc, self.x);
+				ABS_INDEXED_W!(ea, self.pc, self.x);
 				ROR!(ea);
 			}
 			0x7F => { // RRA Absolute, X (undocumented)
-				ABS_INDEXED!(ea, self.pc, self.x);
+				ABS_INDEXED_W!(ea, self.pc, self.x);
 				RRA!(ea);
 			}
 			0x80 => { // NOP Immediate (undocumented)
 				IMM!(ea, self.pc);
+				DUMMY_READ!(ea);
 			}
 			0x81 => { // STA Indirect, X
 				INDIRECT_X!(ea, self.pc);
@@ -1252,6 +1409,7 @@ impl CPU {
 			}
 			0x82 => { // NOP Immediate (undocumented)
 				IMM!(ea, self.pc);
+				DUMMY_READ!(ea);
 			}
 			0x83 => { // AAX Indirect, X (undocumented)
 				INDIRECT_X!(ea, self.pc);
@@ -1278,6 +1436,7 @@ impl CPU {
 			}
 			0x89 => { // NOP Immediate (undocumented)
 				IMM!(ea, self.pc);
+				DUMMY_READ!(ea);
 			}
 			0x8A => { // TXA
 				TXA!();
@@ -1303,7 +1462,7 @@ impl CPU {
 				BCC!(ea);
 			}
 			0x91 => { // STA Indirect Y
-				INDIRECT_Y!(ea, self.pc);
+				INDIRECT_Y_W!(ea, self.pc);
 				STA!(ea);
 			}
 			0x94 => { // STY ZeroPage,X
@@ -1326,14 +1485,14 @@ impl CPU {
 				TYA!();
 			}
 			0x99 => { // STA Absolute, Y
-				ABS_INDEXED!(ea, self.pc, self.y);
+				ABS_INDEXED_W!(ea, self.pc, self.y);
 				STA!(ea);
 			}
 			0x9A => { // TXS
 				TXS!();
 			}
 			0x9D => { // STA Absolute, X
-				ABS_INDEXED!(ea, self.pc, self.x);
+				ABS_INDEXED_W!(ea, self.pc, self.x);
 				STA!(ea);
 			}
 			0xA0 => { // LDY Immediate
@@ -1458,6 +1617,7 @@ impl CPU {
 			}
 			0xC2 => { // NOP Immediate (undocumented)
 				IMM!(ea, self.pc);
+				DUMMY_READ!(ea);
 			}
 			0xC3 => { // DCP Indirect, X (undocumented)
 				INDIRECT_X!(ea, self.pc);
@@ -1514,12 +1674,12 @@ impl CPU {
 				CMP!(ea);
 			}
 			0xD3 => { // DCP Indirect, Y (undocumented)
-				INDIRECT_Y!(ea, self.pc);
+				INDIRECT_Y_W!(ea, self.pc);
 				DCP!(ea);
 			}
 			0xD4 => { // NOP ZeroPage, X (Undocumented)
 				ZERO_PAGE_INDEXED!(ea, self.pc, self.x);
-				// NOP
+				DUMMY_READ!(ea);
 			}
 			0xD5 => { // CMP ZeroPage, X
 				ZERO_PAGE_INDEXED!(ea, self.pc, self.x);
@@ -1543,22 +1703,23 @@ impl CPU {
 			0xDA => { // NOP (undocumented)
 			}
 			0xDB => { // DCP Absolute, Y (undocumented)
-				ABS_INDEXED!(ea, self.pc, self.y);
+				ABS_INDEXED_W!(ea, self.pc, self.y);
 				DCP!(ea);
 			}
 			0xDC => { // NOP Absolute, X (undocumented)
 				ABS_INDEXED!(ea, self.pc, self.x);
+				DUMMY_READ!(ea);
 			}
 			0xDD => { // CMP Absolute, X
 				ABS_INDEXED!(ea, self.pc, self.x);
 				CMP!(ea);
 			}
 			0xDE => { // DEC Absolute, X
-				ABS_INDEXED!(ea, self.pc, self.x);
+				ABS_INDEXED_W!(ea, self.pc, self.x);
 				DEC!(ea);
 			}
 			0xDF => { // DCP Absolute, X (undocumented)
-				ABS_INDEXED!(ea, self.pc, self.x);
+				ABS_INDEXED_W!(ea, self.pc, self.x);
 				DCP!(ea);
 			}
 			0xE0 => { // CPX Immediate
@@ -1627,12 +1788,12 @@ impl CPU {
 				SBC!(ea);
 			}
 			0xF3 => { // ISC Indirect, Y (undocumented)
-				INDIRECT_Y!(ea, self.pc);
+				INDIRECT_Y_W!(ea, self.pc);
 				ISC!(ea);
 			}
 			0xF4 => { // NOP ZeroPage, X (Undocumented)
 				ZERO_PAGE_INDEXED!(ea, self.pc, self.x);
-				// NOP
+				DUMMY_READ!(ea);
 			}
 			0xF5 => { // SBC ZeroPage, X
 				ZERO_PAGE_INDEXED!(ea, self.pc, self.x);
@@ -1656,107 +1817,151 @@ impl CPU {
 			0xFA => { // NOP (undocumented)
 			}
 			0xFB => { // ISC Absolute, Y (undocumented)
-				ABS_INDEXED!(ea, self.pc, self.y);
+				ABS_INDEXED_W!(ea, self.pc, self.y);
 				ISC!(ea);
 			}
 			0xFC => { // NOP Absolute, X (undocumented)
 				ABS_INDEXED!(ea, self.pc, self.x);
+				DUMMY_READ!(ea);
 			}
 			0xFD => { // SBC Absolute, X
 				ABS_INDEXED!(ea, self.pc, self.x);
 				SBC!(ea);
 			}
 			0xFE => { // INC Absolute, X
-				ABS_INDEXED!(ea, self.pc, self.x);
+				ABS_INDEXED_W!(ea, self.pc, self.x);
 				INC!(ea);
 			}
 			0xFF => { // ISC Absolute, X (undocumented)
-				ABS_INDEXED!(ea, self.pc, self.x);
+				ABS_INDEXED_W!(ea, self.pc, self.x);
 				ISC!(ea);
 			}
 			_ => {
 				panic!("unsupported opcode:{:x}", op);
 			}
 		}
-		self.clock_remain = CLOCK_TABLE[op as usize].into();
 		SET_5!(self.p); // bit 5 is always 1
 
+		// OAM DMA after a write to $4014
+		//   The CPU halts for one cycle, plus one more if that leaves it on an
+		//   odd cycle, then alternates reads from the page and writes to $2004.
+		//   513 or 514 cycles in total. Interrupts are still latched meanwhile.
+		if let Some(page) = mmu.take_dma() {
+			DUMMY_READ!(self.pc);
+			if self.cycles & 1 != 0 {
+				DUMMY_READ!(self.pc);
+			}
+			let src: u16 = (page as u16) << 8;
+			for i in 0..256 {
+				let v = READ!(src | i);
+				WRITE!(0x2004, v);
+			}
+		}
+
+		// Interrupts polled during the last cycle of the instruction, or of the
+		// DMA, so an NMI raised during the DMA is taken right after it
+		self.interrupt = self.prev_nmi_pending || self.prev_irq_pending;
+
 		//self.dump();
 	}
+
+	// Sample the interrupt lines at the end of every cycle
+	//   An interrupt is taken after an instruction if it was pending at the
+	//   end of the second to last cycle. So CLI/SEI/PLP affect IRQs only
+	//   after the next instruction, while RTI takes effect immediately.
+	fn poll_interrupts(&mut self, mmu: &MMU) {
+		self.prev_nmi_pending = self.nmi_pending;
+		self.prev_irq_pending = self.irq_pending;
+
+		let nmi_line = mmu.nmi_line();
+		if nmi_line && !self.nmi_line {
+			self.nmi_pending = true;
+		}
+		self.nmi_line = nmi_line;
+		self.irq_pending = mmu.irq_line() && (self.p & FLG_I) == 0;
+	}
 	
 	pub fn reset(&mut self) {
 		self.reset_flag = true;
-		self.clock_remain = 0;
-	}
-
-	pub fn nmi(&mut self) {
-		self.nmi_flag = true;
-		self.clock_remain = 0;
 	}
 
-	pub fn irq(&mut self) {
-		if self.p & FLG_I == 0 {
-			self.irq_flag = true;
-			self.clock_remain = 0;
-		}
+	// Registers back to the power-on state, then the reset sequence
+	pub fn power_on(&mut self) {
+		*self = CPU::new(Rc::clone(&self.mmu));
+		self.reset();
 	}
 
 	pub fn set_pc(&mut self, pc:u16) {
+		// finish a pending reset first, it would load PC from the vector
+		if self.reset_flag {
+			self.clock();
+		}
 		self.pc = pc;
 	}
-	
-	fn do_reset(&mut self) {
-		println!("cpu:reset");
-		let mut mmu = self.mmu.borrow_mut();
-		self.pc = mmu.read_2bytes(RESET_VECTOR);
-		SET_I!(self.p);
-		self.reset_flag = false;
-		self.clock_remain = 6;
-	}
-
-	fn do_nmi(&mut self) {
-		//println!("do_nmi");
-		UNSET_B!(self.p);
-
-		// Push SP
-		let mut mmu = self.mmu.borrow_mut();
-		mmu.push_2bytes(0x0100 + self.sp as u16, self.pc);
-		self.sp -= 2;
-
-		// Push P
-		mmu.write(0x0100 + (self.sp as u16), self.p);
-		self.sp -= 1;
-
-		// SET I Flag
-		SET_I!(self.p);
 
-		// Set PC to NMI Vector
-		self.pc = mmu.read_2bytes(NMI_VECTOR);
-
-		self.nmi_flag = false;
-		self.clock_remain = 6;
+	// CPU clocks since power on
+	pub fn cycles(&self) -> u64 {
+		return self.cycles;
 	}
 
-	fn do_irq(&mut self) {
-		UNSET_B!(self.p);
-
-		// Push SP
-		let mut mmu = self.mmu.borrow_mut();
-		mmu.push_2bytes(0x0100 + self.sp as u16, self.pc);
-		self.sp -= 2;
+	// reset/NMI/IRQ is handled before the next instruction
+	pub fn interrupt_pending(&self) -> bool {
+		return self.reset_flag || self.interrupt;
+	}
 
-		// Push P
-		mmu.write(0x0100 + (self.sp as u16), self.p);
-		self.sp -= 1;
+	pub fn registers(&self) -> Registers {
+		Registers {
+			a: self.a,
+			x: self.x,
+			y: self.y,
+			sp: self.sp,
+			p: self.p,
+			pc: self.pc,
+		}
+	}
 
-		// SET I Flag
-		SET_I!(self.p);
+	pub fn set_registers(&mut self, r: &Registers) {
+		self.a = r.a;
+		self.x = r.x;
+		self.y = r.y;
+		self.sp = r.sp;
+		self.p = r.p;
+		self.pc = r.pc;
+	}
 
-		// Set PC to IRQ Vector
-		self.pc = mmu.read_2bytes(IRQ_VECTOR);
+	pub fn save_state(&self, w: &mut StateWriter) {
+		w.write_u8(self.a);
+		w.write_u8(self.x);
+		w.write_u8(self.y);
+		w.write_u8(self.sp);
+		w.write_u8(self.p);
+		w.write_u16(self.pc);
+		w.write_u64(self.cycles); // OAM DMA alignment depends on the parity
+		w.write_bool(self.reset_flag);
+		w.write_bool(self.nmi_line);
+		w.write_bool(self.nmi_pending);
+		w.write_bool(self.irq_pending);
+		w.write_bool(self.prev_nmi_pending);
+		w.write_bool(self.prev_irq_pending);
+		w.write_bool(self.interrupt);
+	}
 
-		self.irq_flag = false;
-		self.clock_remain = 6;
+	pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		self.a = r.read_u8()?;
+		self.x = r.read_u8()?;
+		self.y = r.read_u8()?;
+		self.sp = r.read_u8()?;
+		self.p = r.read_u8()?;
+		self.pc = r.read_u16()?;
+		self.cycles = r.read_u64()?;
+		self.reset_flag = r.read_bool()?;
+		self.nmi_line = r.read_bool()?;
+		self.nmi_pending = r.read_bool()?;
+		self.irq_pending = r.read_bool()?;
+		self.prev_nmi_pending = r.read_bool()?;
+		self.prev_irq_pending = r.read_bool()?;
+		self.interrupt = r.read_bool()?;
+		return Ok(());
 	}
 
 	fn dump(&self) {
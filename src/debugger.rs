@@ -0,0 +1,415 @@
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+
+use crate::cpu::*;
+use crate::mmu::*;
+use crate::nes::*;
+use crate::disasm::*;
+
+// stdin debugger
+//   Runs the machine instruction by instruction and stops on
+//   PC breakpoints, register conditions, watchpoints or IO.debug_break.
+
+const HELP: &str = "\
+c                 continue
+s [n]             step n instructions
+l [n]             run n scanlines
+b [addr]          set breakpoint (list without addr)
+bc reg op value   conditional breakpoint, e.g. 'bc a == 3F', 'bc sp < F0'
+w addr [r|w|rw]   watchpoint on MMU address (default rw)
+d [addr]          delete breakpoints/watchpoints at addr (all without addr)
+dc n              delete conditional breakpoint n
+i                 list breakpoints
+r [reg value]     show/modify registers (a, x, y, sp, p, pc)
+x addr [len]      dump memory
+m addr v [v ...]  modify memory, registers get a normal write
+u [addr] [n]      disassemble (around PC without addr)
+q                 quit
+Numbers are hex. Empty line repeats the last command.";
+
+#[derive(Clone, Copy)]
+enum Reg {
+	A,
+	X,
+	Y,
+	SP,
+	P,
+	PC,
+}
+
+#[derive(Clone, Copy)]
+enum Cmp {
+	EQ,
+	NE,
+	LT,
+	LE,
+	GT,
+	GE,
+}
+
+struct Condition {
+	reg: Reg,
+	cmp: Cmp,
+	value: u16,
+	text: String,
+}
+
+pub struct Debugger {
+	breakpoints: Vec<u16>,
+	conditions: Vec<Condition>,
+	last_command: String,
+}
+
+impl Debugger {
+	pub fn new() -> Debugger {
+		Debugger {
+			breakpoints: Vec::new(),
+			conditions: Vec::new(),
+			last_command: "".to_string(),
+		}
+	}
+
+	// Returns when the user quits or the machine stops
+	pub fn run(&mut self, nes: &mut NES) {
+		println!("debugger: 'h' for help");
+		self.print_state(nes);
+
+		let stdin = io::stdin();
+		while !nes.quit() {
+			print!("> ");
+			io::stdout().flush().unwrap();
+
+			let mut line = String::new();
+			match stdin.lock().read_line(&mut line) {
+				Ok(0) | Err(_) => return,
+				Ok(_) => {}
+			}
+			let mut line = line.trim().to_string();
+			if line.is_empty() {
+				line = self.last_command.clone();
+			}
+			self.last_command = line.clone();
+
+			let args: Vec<&str> = line.split_whitespace().collect();
+			if args.is_empty() {
+				continue;
+			}
+			if let Err(e) = self.command(nes, &args) {
+				if e.is_empty() {
+					return;
+				}
+				println!("{}", e);
+			}
+		}
+	}
+
+	// Err("") means quit
+	fn command(&mut self, nes: &mut NES, args: &[&str]) -> Result<(), String> {
+		match args[0] {
+			"h" | "help" => {
+				println!("{}", HELP);
+			}
+			"q" | "quit" => {
+				return Err("".to_string());
+			}
+			"c" | "continue" => {
+				self.run_until_break(nes, None);
+			}
+			"s" | "step" => {
+				let n = arg_num(args, 1, 1)?;
+				for _ in 0..n {
+					nes.step();
+				}
+				self.print_state(nes);
+			}
+			"l" | "line" => {
+				let n = arg_num(args, 1, 1)?;
+				self.run_until_break(nes, Some(n as u32));
+			}
+			"b" | "break" => {
+				if args.len() < 2 {
+					self.list(nes);
+				} else {
+					let addr = parse_num(args[1])? as u16;
+					if !self.breakpoints.contains(&addr) {
+						self.breakpoints.push(addr);
+					}
+				}
+			}
+			"bc" => {
+				if args.len() != 4 {
+					return Err("usage: bc reg op value".to_string());
+				}
+				let reg = parse_reg(args[1])?;
+				let cmp = match args[2] {
+					"==" => Cmp::EQ,
+					"!=" => Cmp::NE,
+					"<" => Cmp::LT,
+					"<=" => Cmp::LE,
+					">" => Cmp::GT,
+					">=" => Cmp::GE,
+					_ => return Err(format!("unknown operator: {}", args[2])),
+				};
+				let value = parse_num(args[3])? as u16;
+				self.conditions.push(Condition {
+					reg: reg,
+					cmp: cmp,
+					value: value,
+					text: args[1..].join(" "),
+				});
+			}
+			"w" | "watch" => {
+				if args.len() < 2 {
+					return Err("usage: w addr [r|w|rw]".to_string());
+				}
+				let addr = parse_num(args[1])? as u16;
+				let kind = match args.get(2).copied().unwrap_or("rw") {
+					"r" => WATCH_READ,
+					"w" => WATCH_WRITE,
+					"rw" => WATCH_READ | WATCH_WRITE,
+					k => return Err(format!("unknown watch kind: {}", k)),
+				};
+				nes.mmu().borrow_mut().set_watchpoint(addr, kind);
+			}
+			"d" | "delete" => {
+				if args.len() < 2 {
+					self.breakpoints.clear();
+					let mut mmu = nes.mmu().borrow_mut();
+					let addrs: Vec<u16> = mmu.watchpoints().iter().map(|w| w.0).collect();
+					for addr in addrs {
+						mmu.set_watchpoint(addr, 0);
+					}
+				} else {
+					let addr = parse_num(args[1])? as u16;
+					self.breakpoints.retain(|b| *b != addr);
+					nes.mmu().borrow_mut().set_watchpoint(addr, 0);
+				}
+			}
+			"dc" => {
+				let n = arg_num(args, 1, 0)? as usize;
+				if n >= self.conditions.len() {
+					return Err(format!("no condition {}", n));
+				}
+				self.conditions.remove(n);
+			}
+			"i" | "info" => {
+				self.list(nes);
+			}
+			"r" | "regs" => {
+				if args.len() >= 3 {
+					let reg = parse_reg(args[1])?;
+					let value = parse_num(args[2])?;
+					let mut cpu = nes.cpu().borrow_mut();
+					let mut r = cpu.registers();
+					match reg {
+						Reg::A => r.a = value as u8,
+						Reg::X => r.x = value as u8,
+						Reg::Y => r.y = value as u8,
+						Reg::SP => r.sp = value as u8,
+						Reg::P => r.p = value as u8,
+						Reg::PC => r.pc = value as u16,
+					}
+					cpu.set_registers(&r);
+				}
+				self.print_state(nes);
+			}
+			"x" | "mem" => {
+				if args.len() < 2 {
+					return Err("usage: x addr [len]".to_string());
+				}
+				let addr = parse_num(args[1])? as u16;
+				let len = arg_num(args, 2, 0x40)?;
+				let mmu = nes.mmu().borrow();
+				for row in (0..len).step_by(16) {
+					let base = addr.wrapping_add(row as u16);
+					let mut s = format!("{:04X}:", base);
+					for i in 0..16.min(len - row) {
+						s += &format!(" {:02X}", mmu.peek(base.wrapping_add(i as u16)));
+					}
+					println!("{}", s);
+				}
+			}
+			"m" | "set" => {
+				if args.len() < 3 {
+					return Err("usage: m addr v [v ...]".to_string());
+				}
+				let addr = parse_num(args[1])? as u16;
+				let mut mmu = nes.mmu().borrow_mut();
+				for (i, v) in args[2..].iter().enumerate() {
+					let a = addr.wrapping_add(i as u16);
+					if !mmu.poke(a, parse_num(v)? as u8) {
+						return Err(format!("${:04X}: no PRG-RAM", a));
+					}
+				}
+			}
+			"u" | "disas" => {
+				let pc = nes.cpu().borrow().registers().pc;
+				let mmu = nes.mmu().borrow();
+				let (start, n) = if args.len() >= 2 {
+					(parse_num(args[1])? as u16, arg_num(args, 2, 16)?)
+				} else {
+					(back(&mmu, pc, 5), arg_num(args, 2, 12)?)
+				};
+				let mut addr = start;
+				for _ in 0..n {
+					let (text, len) = disassemble(&mmu, addr);
+					let mark = if addr == pc { ">" } else { " " };
+					println!("{}{:04X}  {}", mark, addr, text);
+					addr = addr.wrapping_add(len);
+				}
+			}
+			_ => {
+				return Err(format!("unknown command: {} ('h' for help)", args[0]));
+			}
+		}
+		return Ok(());
+	}
+
+	// lines: stop after this many scanlines
+	fn run_until_break(&mut self, nes: &mut NES, lines: Option<u32>) {
+		let mut lines = lines;
+		let mut last_line = nes.ppu().borrow().line();
+		nes.io().lock().unwrap().debug_break = false;
+
+		loop {
+			let pc = nes.cpu().borrow().registers().pc;
+			nes.step();
+			if nes.quit() {
+				return;
+			}
+
+			if let Some(hit) = nes.mmu().borrow_mut().take_watch_hit() {
+				let kind = if hit.write { "write" } else { "read" };
+				println!("watch: {} ${:04X} = {:02X} at ${:04X}", kind, hit.addr, hit.value, pc);
+				break;
+			}
+
+			let r = nes.cpu().borrow().registers();
+			if self.breakpoints.contains(&r.pc) {
+				println!("break: ${:04X}", r.pc);
+				break;
+			}
+			if let Some(c) = self.conditions.iter().find(|c| c.eval(&r)) {
+				println!("break: {}", c.text);
+				break;
+			}
+
+			if let Some(n) = lines {
+				let line = nes.ppu().borrow().line();
+				if line != last_line {
+					last_line = line;
+					if n <= 1 {
+						break;
+					}
+					lines = Some(n - 1);
+				}
+			}
+
+			let mut io = nes.io().lock().unwrap();
+			if io.debug_break {
+				io.debug_break = false;
+				println!("break");
+				break;
+			}
+		}
+		self.print_state(nes);
+	}
+
+	fn list(&self, nes: &NES) {
+		for b in self.breakpoints.iter() {
+			println!("break ${:04X}", b);
+		}
+		for (i, c) in self.conditions.iter().enumerate() {
+			println!("cond  {}: {}", i, c.text);
+		}
+		for w in nes.mmu().borrow().watchpoints() {
+			let r = if w.1 & WATCH_READ != 0 { "r" } else { "" };
+			let w_ = if w.1 & WATCH_WRITE != 0 { "w" } else { "" };
+			println!("watch ${:04X} {}{}", w.0, r, w_);
+		}
+	}
+
+	fn print_state(&self, nes: &NES) {
+		let r = nes.cpu().borrow().registers();
+		let (text, _) = disassemble(&nes.mmu().borrow(), r.pc);
+		let ppu = nes.ppu().borrow();
+		println!("{:04X}  {:<14} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} [{}] line:{} dot:{} frame:{}",
+			r.pc, text, r.a, r.x, r.y, r.p, r.sp, flags(r.p), ppu.line(), ppu.dot(), ppu.frames);
+	}
+}
+
+impl Condition {
+	fn eval(&self, r: &Registers) -> bool {
+		let v = match self.reg {
+			Reg::A => r.a as u16,
+			Reg::X => r.x as u16,
+			Reg::Y => r.y as u16,
+			Reg::SP => r.sp as u16,
+			Reg::P => r.p as u16,
+			Reg::PC => r.pc,
+		};
+		match self.cmp {
+			Cmp::EQ => v == self.value,
+			Cmp::NE => v != self.value,
+			Cmp::LT => v < self.value,
+			Cmp::LE => v <= self.value,
+			Cmp::GT => v > self.value,
+			Cmp::GE => v >= self.value,
+		}
+	}
+}
+
+// Instruction start n instructions before addr. 6502 code cannot be decoded
+// backwards, so try start points until one decodes exactly onto addr.
+fn back(mmu: &MMU, addr: u16, n: u16) -> u16 {
+	for dist in n..n * 3 + 1 {
+		let start = addr.wrapping_sub(dist);
+		let mut a = start;
+		let mut count = 0;
+		while a != addr && addr.wrapping_sub(a) <= dist {
+			a = a.wrapping_add(disassemble(mmu, a).1);
+			count += 1;
+		}
+		if a == addr && count >= n {
+			return start;
+		}
+	}
+	return addr;
+}
+
+fn flags(p: u8) -> String {
+	let mut s = String::new();
+	for (i, c) in "NV-BDIZC".chars().enumerate() {
+		if p & (0x80 >> i) != 0 {
+			s.push(c);
+		} else {
+			s.push('.');
+		}
+	}
+	return s;
+}
+
+fn parse_reg(s: &str) -> Result<Reg, String> {
+	match &*s.to_lowercase() {
+		"a" => Ok(Reg::A),
+		"x" => Ok(Reg::X),
+		"y" => Ok(Reg::Y),
+		"sp" => Ok(Reg::SP),
+		"p" => Ok(Reg::P),
+		"pc" => Ok(Reg::PC),
+		_ => Err(format!("unknown register: {}", s)),
+	}
+}
+
+fn parse_num(s: &str) -> Result<usize, String> {
+	let t = s.trim_start_matches('$');
+	return usize::from_str_radix(t, 16).map_err(|_| format!("bad number: {}", s));
+}
+
+fn arg_num(args: &[&str], i: usize, default: usize) -> Result<usize, String> {
+	match args.get(i) {
+		None => Ok(default),
+		Some(s) => parse_num(s),
+	}
+}
@@ -0,0 +1,239 @@
+use std::io;
+use std::io::Write;
+
+use crate::cpu::*;
+use crate::mmu::*;
+
+// 6502 disassembler
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+	IMP, // implied
+	ACC, // accumulator
+	IMM, // #$nn
+	ZP,  // $nn
+	ZPX, // $nn,X
+	ZPY, // $nn,Y
+	ABS, // $nnnn
+	ABX, // $nnnn,X
+	ABY, // $nnnn,Y
+	IND, // ($nnnn)
+	IZX, // ($nn,X)
+	IZY, // ($nn),Y
+	REL, // branch target
+}
+
+use Mode::*;
+
+#[derive(Clone, Copy)]
+pub struct Opcode {
+	pub name: &'static str,
+	pub mode: Mode,
+	pub cycles: u8,     // base cycles, without page crossing / branch penalty. 0: halts the CPU
+	pub official: bool, // false: undocumented
+}
+
+const fn op(name: &'static str, mode: Mode, cycles: u8) -> Opcode {
+	Opcode { name: name, mode: mode, cycles: cycles, official: true }
+}
+
+const fn xop(name: &'static str, mode: Mode, cycles: u8) -> Opcode {
+	Opcode { name: name, mode: mode, cycles: cycles, official: false }
+}
+
+pub const OPCODES: [Opcode; 256] = [
+	/* 0x */
+	op("BRK", IMP, 7), op("ORA", IZX, 6), xop("KIL", IMP, 0), xop("SLO", IZX, 8),
+	xop("NOP", ZP, 3), op("ORA", ZP, 3), op("ASL", ZP, 5), xop("SLO", ZP, 5),
+	op("PHP", IMP, 3), op("ORA", IMM, 2), op("ASL", ACC, 2), xop("ANC", IMM, 2),
+	xop("NOP", ABS, 4), op("ORA", ABS, 4), op("ASL", ABS, 6), xop("SLO", ABS, 6),
+	/* 1x */
+	op("BPL", REL, 2), op("ORA", IZY, 5), xop("KIL", IMP, 0), xop("SLO", IZY, 8),
+	xop("NOP", ZPX, 4), op("ORA", ZPX, 4), op("ASL", ZPX, 6), xop("SLO", ZPX, 6),
+	op("CLC", IMP, 2), op("ORA", ABY, 4), xop("NOP", IMP, 2), xop("SLO", ABY, 7),
+	xop("NOP", ABX, 4), op("ORA", ABX, 4), op("ASL", ABX, 7), xop("SLO", ABX, 7),
+	/* 2x */
+	op("JSR", ABS, 6), op("AND", IZX, 6), xop("KIL", IMP, 0), xop("RLA", IZX, 8),
+	op("BIT", ZP, 3), op("AND", ZP, 3), op("ROL", ZP, 5), xop("RLA", ZP, 5),
+	op("PLP", IMP, 4), op("AND", IMM, 2), op("ROL", ACC, 2), xop("ANC", IMM, 2),
+	op("BIT", ABS, 4), op("AND", ABS, 4), op("ROL", ABS, 6), xop("RLA", ABS, 6),
+	/* 3x */
+	op("BMI", REL, 2), op("AND", IZY, 5), xop("KIL", IMP, 0), xop("RLA", IZY, 8),
+	xop("NOP", ZPX, 4), op("AND", ZPX, 4), op("ROL", ZPX, 6), xop("RLA", ZPX, 6),
+	op("SEC", IMP, 2), op("AND", ABY, 4), xop("NOP", IMP, 2), xop("RLA", ABY, 7),
+	xop("NOP", ABX, 4), op("AND", ABX, 4), op("ROL", ABX, 7), xop("RLA", ABX, 7),
+	/* 4x */
+	op("RTI", IMP, 6), op("EOR", IZX, 6), xop("KIL", IMP, 0), xop("SRE", IZX, 8),
+	xop("NOP", ZP, 3), op("EOR", ZP, 3), op("LSR", ZP, 5), xop("SRE", ZP, 5),
+	op("PHA", IMP, 3), op("EOR", IMM, 2), op("LSR", ACC, 2), xop("ALR", IMM, 2),
+	op("JMP", ABS, 3), op("EOR", ABS, 4), op("LSR", ABS, 6), xop("SRE", ABS, 6),
+	/* 5x */
+	op("BVC", REL, 2), op("EOR", IZY, 5), xop("KIL", IMP, 0), xop("SRE", IZY, 8),
+	xop("NOP", ZPX, 4), op("EOR", ZPX, 4), op("LSR", ZPX, 6), xop("SRE", ZPX, 6),
+	op("CLI", IMP, 2), op("EOR", ABY, 4), xop("NOP", IMP, 2), xop("SRE", ABY, 7),
+	xop("NOP", ABX, 4), op("EOR", ABX, 4), op("LSR", ABX, 7), xop("SRE", ABX, 7),
+	/* 6x */
+	op("RTS", IMP, 6), op("ADC", IZX, 6), xop("KIL", IMP, 0), xop("RRA", IZX, 8),
+	xop("NOP", ZP, 3), op("ADC", ZP, 3), op("ROR", ZP, 5), xop("RRA", ZP, 5),
+	op("PLA", IMP, 4), op("ADC", IMM, 2), op("ROR", ACC, 2), xop("ARR", IMM, 2),
+	op("JMP", IND, 5), op("ADC", ABS, 4), op("ROR", ABS, 6), xop("RRA", ABS, 6),
+	/* 7x */
+	op("BVS", REL, 2), op("ADC", IZY, 5), xop("KIL", IMP, 0), xop("RRA", IZY, 8),
+	xop("NOP", ZPX, 4), op("ADC", ZPX, 4), op("ROR", ZPX, 6), xop("RRA", ZPX, 6),
+	op("SEI", IMP, 2), op("ADC", ABY, 4), xop("NOP", IMP, 2), xop("RRA", ABY, 7),
+	xop("NOP", ABX, 4), op("ADC", ABX, 4), op("ROR", ABX, 7), xop("RRA", ABX, 7),
+	/* 8x */
+	xop("NOP", IMM, 2), op("STA", IZX, 6), xop("NOP", IMM, 2), xop("SAX", IZX, 6),
+	op("STY", ZP, 3), op("STA", ZP, 3), op("STX", ZP, 3), xop("SAX", ZP, 3),
+	op("DEY", IMP, 2), xop("NOP", IMM, 2), op("TXA", IMP, 2), xop("XAA", IMM, 2),
+	op("STY", ABS, 4), op("STA", ABS, 4), op("STX", ABS, 4), xop("SAX", ABS, 4),
+	/* 9x */
+	op("BCC", REL, 2), op("STA", IZY, 6), xop("KIL", IMP, 0), xop("AHX", IZY, 6),
+	op("STY", ZPX, 4), op("STA", ZPX, 4), op("STX", ZPY, 4), xop("SAX", ZPY, 4),
+	op("TYA", IMP, 2), op("STA", ABY, 5), op("TXS", IMP, 2), xop("TAS", ABY, 5),
+	xop("SHY", ABX, 5), op("STA", ABX, 5), xop("SHX", ABY, 5), xop("AHX", ABY, 5),
+	/* Ax */
+	op("LDY", IMM, 2), op("LDA", IZX, 6), op("LDX", IMM, 2), xop("LAX", IZX, 6),
+	op("LDY", ZP, 3), op("LDA", ZP, 3), op("LDX", ZP, 3), xop("LAX", ZP, 3),
+	op("TAY", IMP, 2), op("LDA", IMM, 2), op("TAX", IMP, 2), xop("LAX", IMM, 2),
+	op("LDY", ABS, 4), op("LDA", ABS, 4), op("LDX", ABS, 4), xop("LAX", ABS, 4),
+	/* Bx */
+	op("BCS", REL, 2), op("LDA", IZY, 5), xop("KIL", IMP, 0), xop("LAX", IZY, 5),
+	op("LDY", ZPX, 4), op("LDA", ZPX, 4), op("LDX", ZPY, 4), xop("LAX", ZPY, 4),
+	op("CLV", IMP, 2), op("LDA", ABY, 4), op("TSX", IMP, 2), xop("LAS", ABY, 4),
+	op("LDY", ABX, 4), op("LDA", ABX, 4), op("LDX", ABY, 4), xop("LAX", ABY, 4),
+	/* Cx */
+	op("CPY", IMM, 2), op("CMP", IZX, 6), xop("NOP", IMM, 2), xop("DCP", IZX, 8),
+	op("CPY", ZP, 3), op("CMP", ZP, 3), op("DEC", ZP, 5), xop("DCP", ZP, 5),
+	op("INY", IMP, 2), op("CMP", IMM, 2), op("DEX", IMP, 2), xop("AXS", IMM, 2),
+	op("CPY", ABS, 4), op("CMP", ABS, 4), op("DEC", ABS, 6), xop("DCP", ABS, 6),
+	/* Dx */
+	op("BNE", REL, 2), op("CMP", IZY, 5), xop("KIL", IMP, 0), xop("DCP", IZY, 8),
+	xop("NOP", ZPX, 4), op("CMP", ZPX, 4), op("DEC", ZPX, 6), xop("DCP", ZPX, 6),
+	op("CLD", IMP, 2), op("CMP", ABY, 4), xop("NOP", IMP, 2), xop("DCP", ABY, 7),
+	xop("NOP", ABX, 4), op("CMP", ABX, 4), op("DEC", ABX, 7), xop("DCP", ABX, 7),
+	/* Ex */
+	op("CPX", IMM, 2), op("SBC", IZX, 6), xop("NOP", IMM, 2), xop("ISB", IZX, 8),
+	op("CPX", ZP, 3), op("SBC", ZP, 3), op("INC", ZP, 5), xop("ISB", ZP, 5),
+	op("INX", IMP, 2), op("SBC", IMM, 2), op("NOP", IMP, 2), xop("SBC", IMM, 2),
+	op("CPX", ABS, 4), op("SBC", ABS, 4), op("INC", ABS, 6), xop("ISB", ABS, 6),
+	/* Fx */
+	op("BEQ", REL, 2), op("SBC", IZY, 5), xop("KIL", IMP, 0), xop("ISB", IZY, 8),
+	xop("NOP", ZPX, 4), op("SBC", ZPX, 4), op("INC", ZPX, 6), xop("ISB", ZPX, 6),
+	op("SED", IMP, 2), op("SBC", ABY, 4), xop("NOP", IMP, 2), xop("ISB", ABY, 7),
+	xop("NOP", ABX, 4), op("SBC", ABX, 4), op("INC", ABX, 7), xop("ISB", ABX, 7),
+];
+
+impl Mode {
+	// instruction length including the opcode
+	pub fn len(&self) -> u16 {
+		match self {
+			IMP | ACC => 1,
+			IMM | ZP | ZPX | ZPY | IZX | IZY | REL => 2,
+			ABS | ABX | ABY | IND => 3,
+		}
+	}
+}
+
+// Returns the instruction at addr and its length. Memory is read without side effects.
+pub fn disassemble(mmu: &MMU, addr: u16) -> (String, u16) {
+	let bytes = [mmu.peek(addr), mmu.peek(addr.wrapping_add(1)), mmu.peek(addr.wrapping_add(2))];
+	return decode(addr, &bytes);
+}
+
+// bytes: opcode and up to 2 operand bytes (missing ones read as 0)
+//   Undocumented opcodes are prefixed with '*'.
+pub fn decode(addr: u16, bytes: &[u8]) -> (String, u16) {
+	let byte = |i: usize| if i < bytes.len() { bytes[i] } else { 0 };
+	let op = &OPCODES[byte(0) as usize];
+	let len = op.mode.len();
+	let lo = byte(1);
+	let word = (lo as u16) | ((byte(2) as u16) << 8);
+
+	let operand = match op.mode {
+		IMP => "".to_string(),
+		ACC => "A".to_string(),
+		IMM => format!("#${:02X}", lo),
+		ZP  => format!("${:02X}", lo),
+		ZPX => format!("${:02X},X", lo),
+		ZPY => format!("${:02X},Y", lo),
+		ABS => format!("${:04X}", word),
+		ABX => format!("${:04X},X", word),
+		ABY => format!("${:04X},Y", word),
+		IND => format!("(${:04X})", word),
+		IZX => format!("(${:02X},X)", lo),
+		IZY => format!("(${:02X}),Y", lo),
+		REL => format!("${:04X}", addr.wrapping_add(2).wrapping_add(lo as i8 as u16)),
+	};
+
+	let name = if op.official { op.name.to_string() } else { format!("*{}", op.name) };
+	if operand.is_empty() {
+		return (name, len);
+	} else {
+		return (format!("{} {}", name, operand), len);
+	}
+}
+
+// Linear sweep over every 16k PRG bank.
+//   The last bank is placed at $C000 (fixed bank on most mappers), the others at $8000.
+//   Reset/NMI/IRQ vectors are read from the last bank. Their targets are labeled
+//   in every bank, at either 16k window, since any bank mapped there may hold them.
+pub fn disassemble_prg(w: &mut impl Write, prom: &[u8]) -> io::Result<()> {
+	const BANK_SIZE: usize = 0x4000;
+	let banks = (prom.len() + BANK_SIZE - 1) / BANK_SIZE;
+	if banks == 0 {
+		return Ok(());
+	}
+
+	let vector = |addr: u16| {
+		let offset = (prom.len() + addr as usize).wrapping_sub(0x10000);
+		match (prom.get(offset), prom.get(offset + 1)) {
+			(Some(lo), Some(hi)) => (*lo as u16) | ((*hi as u16) << 8),
+			_ => 0,
+		}
+	};
+	let labels = [
+		(vector(NMI_VECTOR), "nmi"),
+		(vector(RESET_VECTOR), "reset"),
+		(vector(IRQ_VECTOR), "irq"),
+	];
+
+	for bank in 0..banks {
+		let data = &prom[bank * BANK_SIZE .. ((bank + 1) * BANK_SIZE).min(prom.len())];
+		let last = bank == banks - 1;
+		let origin: u16 = if last { 0xC000 } else { 0x8000 };
+		writeln!(w, "; bank {} (${:04X}-${:04X})", bank, origin, origin as usize + data.len() - 1)?;
+
+		let mut i = 0;
+		while i < data.len() {
+			let addr = origin.wrapping_add(i as u16);
+			for (label_addr, label) in labels.iter() {
+				if *label_addr == addr {
+					writeln!(w, "{}:", label)?;
+				} else if *label_addr == addr ^ 0x4000 {
+					// the bank mapped to the other 16k window
+					writeln!(w, "{}: ; when mapped at ${:04X}", label, label_addr)?;
+				}
+			}
+			if last && addr >= NMI_VECTOR && i + 1 < data.len() {
+				let v = (data[i] as u16) | ((data[i + 1] as u16) << 8);
+				let name = match addr {
+					NMI_VECTOR => "nmi",
+					RESET_VECTOR => "reset",
+					_ => "irq",
+				};
+				writeln!(w, "{:04X}  {:02X} {:02X}     .dw ${:04X} ; {}", addr, data[i], data[i + 1], v, name)?;
+				i += 2;
+				continue;
+			}
+
+			let (text, len) = decode(addr, &data[i..]);
+			let len = (len as usize).min(data.len() - i);
+			let raw: Vec<String> = data[i..i + len].iter().map(|b| format!("{:02X}", b)).collect();
+			writeln!(w, "{:04X}  {:<8}  {}", addr, raw.join(" "), text)?;
+			i += len;
+		}
+		writeln!(w)?;
+	}
+	return Ok(());
+}
@@ -0,0 +1,113 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Condvar;
+
+use crate::cpu::*;
+use crate::ppu::*;
+use crate::apu::*;
+use crate::mmu::*;
+use crate::nes::*;
+use crate::io::*;
+use crate::cartridge::*;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+// Frontend independent entry point.
+//   framebuffer: RGB888, SCREEN_WIDTH x SCREEN_HEIGHT
+//   audio: mono f32 samples generated during the last frame
+pub struct Emulator {
+	nes: NES,
+	io: Arc<Mutex<IO>>,
+
+	framebuffer: Vec<u8>,
+	audio: Vec<f32>,
+}
+
+impl Emulator {
+	// Headless machine. Frames are not synchronized to anything.
+	pub fn new() -> Emulator {
+		let io = Arc::new(Mutex::new(IO::new()));
+		let vbr = Arc::new((Mutex::new(VBR::new()), Condvar::new()));
+		let mut emu = Emulator::with_io(io, vbr);
+		emu.nes.nowait(true);
+		return emu;
+	}
+
+	// Machine sharing IO and VBR with a frontend (see renderer.rs)
+	pub fn with_io(io: Arc<Mutex<IO>>, vbr: Arc<(Mutex<VBR>, Condvar)>) -> Emulator {
+		let ppu = Rc::new(RefCell::new(PPU::new(Arc::clone(&io), vbr)));
+		let apu = Rc::new(RefCell::new(APU::new(Arc::clone(&io))));
+		let mmu = Rc::new(RefCell::new(MMU::new(Rc::clone(&ppu), Rc::clone(&apu), Arc::clone(&io))));
+		let cpu = Rc::new(RefCell::new(CPU::new(Rc::clone(&mmu))));
+		let nes = NES::new(cpu, mmu, ppu, apu, Arc::clone(&io));
+
+		Emulator {
+			nes: nes,
+			io: io,
+			framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+			audio: Vec::new(),
+		}
+	}
+
+	// Insert a ROM image (iNES / NES 2.0) and reset
+	pub fn load_rom(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+		self.nes.load_rom(data)?;
+		self.nes.reset();
+		return Ok(());
+	}
+
+	// Insert a ROM file and reset. Battery backup is kept next to the ROM with
+	// the extension replaced by .sav (game.nes -> game.sav).
+	pub fn load_file(&mut self, path: &str) -> Result<(), CartridgeError> {
+		self.nes.load_cartridge(path)?;
+		self.nes.reset();
+		return Ok(());
+	}
+
+	pub fn reset(&mut self) {
+		self.nes.reset();
+	}
+
+	// Run until the PPU finishes the current frame
+	pub fn run_frame(&mut self) {
+		let frames = self.nes.frames();
+		while self.nes.frames() == frames && !self.nes.quit() {
+			self.nes.clock();
+		}
+
+		let mut io = self.io.lock().unwrap();
+		self.framebuffer.copy_from_slice(&io.vram);
+		self.audio.clear();
+		while let Some(v) = io.audio.read() {
+			self.audio.push(v);
+		}
+	}
+
+	pub fn framebuffer(&self) -> &[u8] {
+		return &self.framebuffer;
+	}
+
+	pub fn audio_samples(&self) -> &[f32] {
+		return &self.audio;
+	}
+
+	// port: 0 or 1, other ports are ignored. mask: BUTTON_* in pad.rs
+	// The game sees the buttons from the next frame on.
+	pub fn set_buttons(&mut self, port: u32, mask: u8) {
+		if port >= 2 {
+			return;
+		}
+		self.io.lock().unwrap().pad.set_buttons(port, mask);
+	}
+
+	pub fn frames(&self) -> u32 {
+		return self.nes.frames();
+	}
+
+	pub fn nes(&mut self) -> &mut NES {
+		return &mut self.nes;
+	}
+}
@@ -12,16 +12,25 @@ const STENCIL_BG: u8 = 2;
 const STENCIL_FRONT_SPRITE: u8 = 3;
 const AUDIO_BUFFER_SIZE: usize = 4096;
 
+pub enum StateRequest {
+	Save(u32), // slot
+	Load(u32),
+}
+
 pub struct IO {
 	pub vram: Vec<u8>,
 	pub stencil: Vec<u8>,
 	pub audio: RingBuffer<f32>,
 	pub pad: Pad,
+	pub state_request: Option<StateRequest>,
+	pub rewind: bool, // rewind key is held
+	pub debug_break: bool, // break into the debugger
 }
 
 pub struct VBR {
 	pub in_vbr: bool,
-	pub frames: u32
+	pub frames: u32,
+	pub quit: bool,
 }
 
 impl IO {
@@ -35,6 +44,9 @@ impl IO {
 			audio: RingBuffer::new(AUDIO_BUFFER_SIZE, 0.0),
 
 			pad: Pad::new(),
+			state_request: None,
+			rewind: false,
+			debug_break: false,
 		};
 		
 		return ret;
@@ -113,7 +125,8 @@ impl VBR {
 	pub fn new() -> VBR {
 		VBR {
 			in_vbr: false,
-			frames: 0
+			frames: 0,
+			quit: false,
 		}
 	}
 }
@@ -0,0 +1,35 @@
+extern crate memmap;
+
+pub mod mmu;
+pub mod cartridge;
+pub mod mapper;
+pub mod mapper_nrom;
+pub mod mapper_mmc1;
+pub mod mapper_uxrom;
+pub mod mapper_cnrom;
+pub mod mapper_mmc3;
+pub mod mapper_axrom;
+pub mod mapper_colordreams;
+pub mod mapper_gxrom;
+pub mod cpu;
+pub mod ppu;
+pub mod apu;
+pub mod apu_frame;
+pub mod apu_envelope;
+pub mod apu_square;
+pub mod apu_triangle;
+pub mod apu_noise;
+pub mod pad;
+pub mod nes;
+pub mod io;
+pub mod ringbuffer;
+pub mod state;
+pub mod emulator;
+pub mod screenshot;
+pub mod movie;
+pub mod rewind;
+pub mod disasm;
+pub mod debugger;
+pub mod trace;
+
+pub use crate::emulator::Emulator;
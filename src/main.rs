@@ -1,40 +1,20 @@
-extern crate memmap;
-
-mod mmu;
-mod cpu;
-mod ppu;
-mod apu;
-mod apu_frame;
-mod apu_envelope;
-mod apu_square;
-mod apu_triangle;
-mod apu_noise;
-mod pad;
-mod nes;
+extern crate rustnes;
+
 mod renderer;
-mod io;
-mod events;
-mod ringbuffer;
 
-use std::cell::RefCell;
-use std::rc::Rc;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::Condvar;
 use std::env;
+use std::fs;
+use std::io;
 use std::thread;
-use std::time::Duration;
-
-use crate::cpu::*;
-use crate::ppu::*;
-use crate::apu::*;
-use crate::pad::*;
-use crate::mmu::*;
-use crate::nes::*;
+
+use rustnes::Emulator;
+use rustnes::io::*;
+use rustnes::movie::*;
+use rustnes::debugger::*;
 use crate::renderer::*;
-use crate::io::*;
-use crate::events::*;
-use crate::ringbuffer::*;
 
 struct Configure {
 	cartridge: String,
@@ -43,6 +23,11 @@ struct Configure {
 	nestest: bool,
 	nowait: bool,
 	profile: bool,
+	debug: bool,
+	rewind: bool,
+	play: Option<String>,
+	record: Option<String>,
+	trace: Option<String>,
 }
 
 fn main() {
@@ -53,31 +38,36 @@ fn main() {
 		nestest: false,
 		nowait: false,
 		profile: false,
+		debug: false,
+		rewind: false,
+		play: None,
+		record: None,
+		trace: None,
 	};
 	analyze_arg(&mut config);
 	if config.cartridge.is_empty() {
-		println!("Usage: rustnes [--entry address] cartridge");
+		println!("Usage: rustnes [--entry address] [--debug] [--rewind] [--play movie.fm2 | --record movie.fm2] [--trace file.log] cartridge");
 		return;
 	}
 
-	let mut vbr = Arc::new((Mutex::new(VBR::new()), Condvar::new()));
-	let mut io = Arc::new(Mutex::new(IO::new()));
+	let vbr = Arc::new((Mutex::new(VBR::new()), Condvar::new()));
+	let io = Arc::new(Mutex::new(IO::new()));
 	let mut renderer = Renderer::new(Arc::clone(&io), Arc::clone(&vbr));
-	let mut event_queue = Arc::new(Mutex::new(EventQueue::new()));
+	let vbr_main = Arc::clone(&vbr);
 
-	thread::spawn(move|| {
-		let ppu = Rc::new(RefCell::new(PPU::new(Arc::clone(&io), Arc::clone(&event_queue), Arc::clone(&vbr))));
-		let apu = Rc::new(RefCell::new(APU::new(Arc::clone(&io), Arc::clone(&event_queue))));
-		let pad = Rc::new(RefCell::new(Pad::new()));
-		let mmu = Rc::new(RefCell::new(MMU::new(Rc::clone(&ppu), Rc::clone(&apu), Arc::clone(&io), Arc::clone(&event_queue))));
-		let cpu = Rc::new(RefCell::new(CPU::new(Rc::clone(&mmu))));
-		let mut nes = NES::new(Rc::clone(&cpu), Rc::clone(&mmu), Rc::clone(&ppu), Rc::clone(&apu), Arc::clone(&event_queue));
+	let emu_thread = thread::spawn(move|| {
+		let mut emu = Emulator::with_io(io, vbr);
+		if let Err(e) = emu.load_file(&config.cartridge) {
+			println!("{}: {}", config.cartridge, e);
+			std::process::exit(1);
+		}
 
-		nes.load_cartridge(&config.cartridge);
+		let nes = emu.nes();
+		if config.rewind {
+			nes.set_rewind(true);
+		}
 		if config.use_entry | config.nestest {
-			cpu.borrow_mut().set_pc(config.entry);
-		} else {
-			nes.reset();
+			nes.set_pc(config.entry);
 		}
 
 		if config.nowait {
@@ -87,18 +77,57 @@ fn main() {
 			nes.profile(true);
 		}
 
-		if config.nestest {
-			loop {
+		if let Some(path) = &config.play {
+			match Movie::load(path) {
+				Ok(movie) => nes.play_movie(movie),
+				Err(e) => {
+					println!("{}: {}", path, e);
+					std::process::exit(1);
+				}
+			}
+		} else if let Some(path) = &config.record {
+			nes.record_movie(path);
+		}
+
+		if let Some(path) = &config.trace {
+			match fs::File::create(path) {
+				Ok(f) => nes.set_trace(Some(Box::new(io::BufWriter::new(f)))),
+				Err(e) => {
+					println!("{}: {}", path, e);
+					std::process::exit(1);
+				}
+			}
+		}
+
+		if config.debug {
+			Debugger::new().run(nes);
+		} else if config.nestest {
+			while !nes.quit() {
 				nes.clock_nestest();
 			}
 		} else {
-			loop {
+			while !nes.quit() {
 				nes.clock();
 			}
 		}
+		nes.stop_movie();
+		nes.set_trace(None);
+		nes.save_sram();
+		if config.debug {
+			// quit from the debugger prompt
+			std::process::exit(0);
+		}
 	});
 
 	renderer.event_loop();
+
+	// Stop the emulator thread so that PRG-RAM is flushed
+	{
+		let (vbr, cond) = &*vbr_main;
+		vbr.lock().unwrap().quit = true;
+		cond.notify_all();
+	}
+	emu_thread.join().unwrap();
 }
 
 fn analyze_arg(config:&mut Configure) {
@@ -106,7 +135,10 @@ fn analyze_arg(config:&mut Configure) {
 
 	enum Option {
 		NONE,
-		ENTRY
+		ENTRY,
+		PLAY,
+		RECORD,
+		TRACE,
 	}
 	let mut cnt = 0;
 	let mut option = Option::NONE;
@@ -132,12 +164,36 @@ fn analyze_arg(config:&mut Configure) {
 			"--profile" => {
 				config.profile = true;
 			}
+			"--debug" => {
+				config.debug = true;
+			}
+			"--rewind" => {
+				config.rewind = true;
+			}
+			"--play" => {
+				option = Option::PLAY;
+			}
+			"--record" => {
+				option = Option::RECORD;
+			}
+			"--trace" => {
+				option = Option::TRACE;
+			}
 			_ => {
 				match option {
 					Option::ENTRY => {
 						config.use_entry = true;
 						config.entry = u16::from_str_radix(&arg, 16).unwrap();
 					}
+					Option::PLAY => {
+						config.play = Some(arg);
+					}
+					Option::RECORD => {
+						config.record = Some(arg);
+					}
+					Option::TRACE => {
+						config.trace = Some(arg);
+					}
 					Option::NONE => {
 						config.cartridge = arg;
 					}
@@ -0,0 +1,187 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::io;
+
+use crate::ppu::*;
+use crate::cartridge::*;
+use crate::state::*;
+use crate::mapper_nrom::*;
+use crate::mapper_mmc1::*;
+use crate::mapper_uxrom::*;
+use crate::mapper_cnrom::*;
+use crate::mapper_mmc3::*;
+use crate::mapper_axrom::*;
+use crate::mapper_colordreams::*;
+use crate::mapper_gxrom::*;
+
+pub trait Mapper {
+	// Called when the cartridge is inserted. Pushes the initial CHR banks to the PPU.
+	fn init(&mut self, ppu: &mut PPU);
+
+	// CPU read from $8000-$FFFF
+	fn read(&self, addr: u16) -> u8;
+
+	// CPU write to $8000-$FFFF
+	fn write(&mut self, addr: u16, v: u8, ppu: &mut PPU);
+
+	// Cartridge CHR memory mapped to PPU $0000-$1FFF
+	fn chr(&mut self) -> &mut CHR;
+
+	// PPU write to $0000-$1FFF. pattern is the PPU copy of $0000-$1FFF.
+	fn write_chr(&mut self, addr: u16, v: u8, pattern: &mut [u8]) {
+		self.chr().write(addr, v, pattern);
+	}
+
+	// PPU address line A12 went from low to high during a pattern fetch
+	fn a12_rise(&mut self) {
+	}
+
+	// Called once per CPU cycle, before the bus access of that cycle
+	fn cpu_clock(&mut self) {
+	}
+
+	// Physical nametable (0-3) for logical nametable $2000/$2400/$2800/$2C00 (0-3).
+	// None leaves it to the PPU mirroring.
+	fn nametable(&self, _table: usize) -> Option<usize> {
+		return None;
+	}
+
+	// PRG-RAM at $6000-$7FFF. Reads open bus when disabled.
+	fn prg_ram_enabled(&self) -> bool {
+		return true;
+	}
+
+	// Writes to PRG-RAM are ignored when false
+	fn prg_ram_writable(&self) -> bool {
+		return true;
+	}
+
+	// IRQ output. Stays asserted until the game acknowledges it.
+	fn irq(&self) -> bool {
+		return false;
+	}
+
+	fn save_state(&self, w: &mut StateWriter);
+	fn load_state(&mut self, r: &mut StateReader) -> io::Result<()>;
+}
+
+const CHR_SLOT_SIZE: usize = 0x0400;
+const CHR_RAM_DEFAULT_SIZE: usize = 0x2000;
+
+// CHR-ROM, or CHR-RAM if the cartridge has no CHR-ROM.
+// Banks are copied into the PPU pattern memory. PPU writes to CHR-RAM are
+// written through so that the banks stay in sync.
+pub struct CHR {
+	pub data: Vec<u8>,
+	pub ram: bool,
+	slot: [usize; 8], // offset in data for each 1k of $0000-$1FFF
+}
+
+impl CHR {
+	pub fn new(crom: &[u8], ram_size: usize) -> CHR {
+		let (data, ram) = if crom.is_empty() {
+			let size = if ram_size < CHR_RAM_DEFAULT_SIZE { CHR_RAM_DEFAULT_SIZE } else { ram_size };
+			(vec![0; size], true)
+		} else {
+			(crom.to_vec(), false)
+		};
+
+		CHR {
+			data: data,
+			ram: ram,
+			slot: [0, 0x400, 0x800, 0xC00, 0x1000, 0x1400, 0x1800, 0x1C00],
+		}
+	}
+
+	// Number of banks of the given size
+	pub fn banks(&self, size: usize) -> usize {
+		return self.data.len() / size;
+	}
+
+	// Map bank to PPU addr. size must be a multiple of 1k.
+	// CHR smaller than size is mirrored.
+	pub fn set_bank(&mut self, ppu: &mut PPU, addr: usize, bank: usize, size: usize) {
+		let start = (bank % self.banks(size).max(1)) * size;
+		for i in 0..size / CHR_SLOT_SIZE {
+			let offset = (start + i * CHR_SLOT_SIZE) % self.data.len();
+			self.slot[addr / CHR_SLOT_SIZE + i] = offset;
+			ppu.set_chr_bank(addr + i * CHR_SLOT_SIZE, &self.data[offset .. offset + CHR_SLOT_SIZE]);
+		}
+	}
+
+	// The banks themselves are restored with the PPU memory
+	pub fn save_state(&self, w: &mut StateWriter) {
+		if self.ram {
+			w.write_bytes(&self.data);
+		}
+		for i in 0..8 {
+			w.write_usize(self.slot[i]);
+		}
+	}
+
+	pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		if self.ram {
+			r.read_bytes(&mut self.data)?;
+		}
+		for i in 0..8 {
+			let slot = r.read_usize()?;
+			if slot + CHR_SLOT_SIZE > self.data.len() {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, "bad CHR bank"));
+			}
+			self.slot[i] = slot;
+		}
+		return Ok(());
+	}
+
+	// Writes to CHR-RAM go to every slot the bank is mapped to, ignored for CHR-ROM
+	pub fn write(&mut self, addr: u16, v: u8, pattern: &mut [u8]) {
+		if !self.ram {
+			return;
+		}
+		let addr = addr as usize;
+		let offset = self.slot[addr / CHR_SLOT_SIZE] + addr % CHR_SLOT_SIZE;
+		self.data[offset] = v;
+		for i in 0..8 {
+			if offset >= self.slot[i] && offset < self.slot[i] + CHR_SLOT_SIZE {
+				pattern[i * CHR_SLOT_SIZE + offset - self.slot[i]] = v;
+			}
+		}
+	}
+}
+
+pub fn is_supported_mapper(no: u16) -> bool {
+	match no {
+		0 | 1 | 2 | 3 | 4 | 7 | 11 | 66 => true,
+		_ => false,
+	}
+}
+
+// Smallest PRG and CHR size a mapper can bank. ROM sizes must be a multiple of it.
+// Less than a whole window is mirrored.
+pub fn mapper_bank_size(no: u16) -> (usize, usize) {
+	match no {
+		0 => (0x2000, 0x2000),
+		1 => (0x4000, 0x1000),
+		3 => (0x2000, 0x2000),
+		4 => (0x4000, 0x0400), // the last two 8k banks are fixed
+		_ => (0x4000, 0x2000),
+	}
+}
+
+pub fn new_mapper(cartridge: &Cartridge) -> Option<Rc<RefCell<dyn Mapper>>> {
+	let header = &cartridge.header;
+	let prom = &cartridge.prom;
+	let chr = CHR::new(&cartridge.crom, header.chr_ram_size + header.chr_nvram_size);
+
+	match header.mapper {
+		0 => Some(Rc::new(RefCell::new(NROM::new(prom, chr)))),
+		1 => Some(Rc::new(RefCell::new(MMC1::new(prom, chr)))),
+		2 => Some(Rc::new(RefCell::new(UxROM::new(prom, chr)))),
+		3 => Some(Rc::new(RefCell::new(CNROM::new(prom, chr)))),
+		4 => Some(Rc::new(RefCell::new(MMC3::new(prom, chr)))),
+		7 => Some(Rc::new(RefCell::new(AxROM::new(prom, chr)))),
+		11 => Some(Rc::new(RefCell::new(ColorDreams::new(prom, chr)))),
+		66 => Some(Rc::new(RefCell::new(GxROM::new(prom, chr)))),
+		_ => None,
+	}
+}
@@ -0,0 +1,50 @@
+use crate::ppu::*;
+use crate::mapper::*;
+use crate::state::*;
+use std::io;
+
+const CROM_BANK_SIZE: usize = 0x2000;
+
+// Mapper 3
+pub struct CNROM {
+	prom: Vec<u8>,
+	chr: CHR,
+}
+
+impl CNROM {
+	pub fn new(prom: &[u8], chr: CHR) -> CNROM {
+		CNROM {
+			prom: prom.to_vec(),
+			chr: chr,
+		}
+	}
+}
+
+impl Mapper for CNROM {
+	fn init(&mut self, ppu: &mut PPU) {
+		self.chr.set_bank(ppu, 0x0000, 0, CROM_BANK_SIZE);
+	}
+
+	fn read(&self, addr: u16) -> u8 {
+		// 16k PROM is mirrored to $C000-$FFFF
+		return self.prom[(addr - 0x8000) as usize % self.prom.len()];
+	}
+
+	fn chr(&mut self) -> &mut CHR {
+		return &mut self.chr;
+	}
+
+	fn write(&mut self, _addr: u16, v: u8, ppu: &mut PPU) {
+		// switch 8k CHR
+		self.chr.set_bank(ppu, 0x0000, v as usize, CROM_BANK_SIZE);
+	}
+
+	fn save_state(&self, w: &mut StateWriter) {
+		self.chr.save_state(w);
+	}
+
+	fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		self.chr.load_state(r)?;
+		return Ok(());
+	}
+}
@@ -0,0 +1,62 @@
+use crate::ppu::*;
+use crate::mapper::*;
+use crate::state::*;
+use std::io;
+
+const PROM_BANK_SIZE: usize = 0x8000;
+const CROM_BANK_SIZE: usize = 0x2000;
+
+const CHR_BANK_MASK: u8 = 0x03;
+const PRG_BANK_MASK: u8 = 0x30;
+
+// Mapper 66
+pub struct GxROM {
+	prom: Vec<u8>,
+	chr: CHR,
+
+	prg_offset: usize,
+}
+
+impl GxROM {
+	pub fn new(prom: &[u8], chr: CHR) -> GxROM {
+		GxROM {
+			prom: prom.to_vec(),
+			chr: chr,
+
+			prg_offset: 0,
+		}
+	}
+}
+
+impl Mapper for GxROM {
+	fn init(&mut self, ppu: &mut PPU) {
+		self.chr.set_bank(ppu, 0x0000, 0, CROM_BANK_SIZE);
+	}
+
+	fn read(&self, addr: u16) -> u8 {
+		// PROM smaller than 32k is mirrored
+		return self.prom[(self.prg_offset + (addr - 0x8000) as usize) % self.prom.len()];
+	}
+
+	fn chr(&mut self) -> &mut CHR {
+		return &mut self.chr;
+	}
+
+	fn write(&mut self, _addr: u16, v: u8, ppu: &mut PPU) {
+		let banks = (self.prom.len() + PROM_BANK_SIZE - 1) / PROM_BANK_SIZE;
+		self.prg_offset = (((v & PRG_BANK_MASK) >> 4) as usize % banks) * PROM_BANK_SIZE;
+
+		self.chr.set_bank(ppu, 0x0000, (v & CHR_BANK_MASK) as usize, CROM_BANK_SIZE);
+	}
+
+	fn save_state(&self, w: &mut StateWriter) {
+		w.write_usize(self.prg_offset);
+		self.chr.save_state(w);
+	}
+
+	fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		self.prg_offset = r.read_usize()? % self.prom.len();
+		self.chr.load_state(r)?;
+		return Ok(());
+	}
+}
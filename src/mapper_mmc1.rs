@@ -0,0 +1,214 @@
+use crate::ppu::*;
+use crate::mapper::*;
+use crate::state::*;
+use std::io;
+
+const PROM_BANK_SIZE: usize = 0x4000;
+const PROM_OUTER_BANKS: usize = 16; // 256k, SUROM selects the half with CHR bank bit 4
+const CROM_BANK_SIZE: usize = 0x1000;
+
+const SHIFT_RESET: u8 = 0x80;
+
+/* Control Register $8000-$9FFF */
+const CONTROL_MIRROR_MASK: u8 = 0x03;
+const CONTROL_PRG_MODE_MASK: u8 = 0x0C;
+const CONTROL_CHR_MODE_4K: u8 = 0x10; // 0: 8k, 1: 4k x 2
+
+/* CHR Bank Register 0 $A000-$BFFF */
+const CHR_PRG_OUTER: u8 = 0x10; // SUROM: 256k PRG bank
+
+/* PRG Bank Register $E000-$FFFF */
+const PRG_BANK_MASK: u8 = 0x0F;
+
+// Mapper 1
+pub struct MMC1 {
+	prom: Vec<u8>,
+	chr: CHR,
+
+	shift: u8,
+	shift_count: u8,
+	write_delay: u8, // cycles since the last write, up to 2
+
+	control: u8,
+	chr_bank0: u8,
+	chr_bank1: u8,
+	prg_bank: u8,
+
+	prg_offset: [usize; 2], // $8000, $C000
+}
+
+impl MMC1 {
+	pub fn new(prom: &[u8], chr: CHR) -> MMC1 {
+		MMC1 {
+			prom: prom.to_vec(),
+			chr: chr,
+
+			shift: 0,
+			shift_count: 0,
+			write_delay: 2,
+
+			control: CONTROL_PRG_MODE_MASK,
+			chr_bank0: 0,
+			chr_bank1: 0,
+			prg_bank: 0,
+
+			prg_offset: [0, 0],
+		}
+	}
+
+	fn update_prg(&mut self) {
+		let banks = self.prom.len() / PROM_BANK_SIZE;
+		let bank = (self.prg_bank & PRG_BANK_MASK) as usize;
+
+		// 512k PRG (SUROM) is two 256k halves, the first/last bank are fixed within the half
+		let (outer, last) = if banks > PROM_OUTER_BANKS {
+			let outer = if self.chr_bank0 & CHR_PRG_OUTER != 0 { PROM_OUTER_BANKS } else { 0 };
+			(outer, PROM_OUTER_BANKS - 1)
+		} else {
+			(0, banks - 1)
+		};
+
+		let (lo, hi) = match (self.control & CONTROL_PRG_MODE_MASK) >> 2 {
+			0 | 1 => {
+				// switch 32k at $8000, ignoring low bit of bank number
+				(bank & !1, (bank & !1) + 1)
+			}
+			2 => {
+				// fix first bank at $8000, switch 16k at $C000
+				(0, bank)
+			}
+			_ => {
+				// switch 16k at $8000, fix last bank at $C000
+				(bank, last)
+			}
+		};
+		self.prg_offset[0] = ((outer + lo) % banks) * PROM_BANK_SIZE;
+		self.prg_offset[1] = ((outer + hi) % banks) * PROM_BANK_SIZE;
+	}
+
+	fn update_chr(&mut self, ppu: &mut PPU) {
+		let (lo, hi) = if self.control & CONTROL_CHR_MODE_4K == 0 {
+			// switch 8k, ignoring low bit of bank number
+			let bank = (self.chr_bank0 & 0x1E) as usize;
+			(bank, bank + 1)
+		} else {
+			(self.chr_bank0 as usize, self.chr_bank1 as usize)
+		};
+		self.chr.set_bank(ppu, 0x0000, lo, CROM_BANK_SIZE);
+		self.chr.set_bank(ppu, 0x1000, hi, CROM_BANK_SIZE);
+	}
+
+	fn update_mirror(&mut self, ppu: &mut PPU) {
+		match self.control & CONTROL_MIRROR_MASK {
+			0 => {
+				ppu.set_mirror(Mirror::SINGLE_SCREEN_LOWER);
+			}
+			1 => {
+				ppu.set_mirror(Mirror::SINGLE_SCREEN_UPPER);
+			}
+			2 => {
+				ppu.set_mirror(Mirror::VARTICAL);
+			}
+			_ => {
+				ppu.set_mirror(Mirror::HORIZONTAL);
+			}
+		}
+	}
+}
+
+impl Mapper for MMC1 {
+	fn init(&mut self, ppu: &mut PPU) {
+		self.update_prg();
+		self.update_chr(ppu);
+	}
+
+	fn read(&self, addr: u16) -> u8 {
+		let bank = ((addr - 0x8000) as usize) / PROM_BANK_SIZE;
+		return self.prom[self.prg_offset[bank] + (addr as usize) % PROM_BANK_SIZE];
+	}
+
+	fn chr(&mut self) -> &mut CHR {
+		return &mut self.chr;
+	}
+
+	fn cpu_clock(&mut self) {
+		if self.write_delay < 2 {
+			self.write_delay += 1;
+		}
+	}
+
+	fn write(&mut self, addr: u16, v: u8, ppu: &mut PPU) {
+		// Writes on consecutive cycles (the two writes of a RMW instruction)
+		// are ignored after the first one.
+		let consecutive = self.write_delay < 2;
+		self.write_delay = 0;
+		if consecutive {
+			return;
+		}
+
+		if v & SHIFT_RESET != 0 {
+			self.shift = 0;
+			self.shift_count = 0;
+			self.control |= CONTROL_PRG_MODE_MASK;
+			self.update_prg();
+			return;
+		}
+
+		// 5 serial writes, LSB first
+		self.shift |= (v & 0x01) << self.shift_count;
+		self.shift_count += 1;
+		if self.shift_count < 5 {
+			return;
+		}
+
+		let n = self.shift;
+		self.shift = 0;
+		self.shift_count = 0;
+
+		match addr {
+			0x8000 ..= 0x9FFF => {
+				self.control = n;
+				self.update_mirror(ppu);
+				self.update_prg();
+				self.update_chr(ppu);
+			}
+			0xA000 ..= 0xBFFF => {
+				self.chr_bank0 = n;
+				self.update_prg();
+				self.update_chr(ppu);
+			}
+			0xC000 ..= 0xDFFF => {
+				self.chr_bank1 = n;
+				self.update_chr(ppu);
+			}
+			_ => {
+				self.prg_bank = n;
+				self.update_prg();
+			}
+		}
+	}
+
+	fn save_state(&self, w: &mut StateWriter) {
+		w.write_u8(self.shift);
+		w.write_u8(self.shift_count);
+		w.write_u8(self.write_delay);
+		w.write_u8(self.control);
+		w.write_u8(self.chr_bank0);
+		w.write_u8(self.chr_bank1);
+		w.write_u8(self.prg_bank);
+		self.chr.save_state(w);
+	}
+
+	fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		self.shift = r.read_u8()?;
+		self.shift_count = r.read_u8()? % 5;
+		self.write_delay = r.read_u8()?.min(2);
+		self.control = r.read_u8()?;
+		self.chr_bank0 = r.read_u8()?;
+		self.chr_bank1 = r.read_u8()?;
+		self.prg_bank = r.read_u8()?;
+		self.update_prg();
+		self.chr.load_state(r)?;
+		return Ok(());
+	}
+}
@@ -0,0 +1,195 @@
+use crate::ppu::*;
+use crate::mapper::*;
+use crate::state::*;
+use std::io;
+
+const PROM_BANK_SIZE: usize = 0x2000;
+const CROM_BANK_SIZE: usize = 0x0400;
+
+/* Bank Select $8000-$9FFE, even */
+const BANK_SELECT_REG_MASK: u8 = 0x07;
+const BANK_SELECT_PRG_MODE: u8 = 0x40; // 0: $8000 swappable, 1: $C000 swappable
+const BANK_SELECT_CHR_INVERSION: u8 = 0x80; // 0: 2k banks at $0000, 1: 2k banks at $1000
+
+/* Mirroring $A000-$BFFE, even */
+const MIRROR_HORIZONTAL: u8 = 0x01;
+
+/* PRG RAM protect $A001-$BFFF, odd */
+const PRG_RAM_WRITE_PROTECT: u8 = 0x40;
+const PRG_RAM_ENABLE: u8 = 0x80;
+
+// Mapper 4
+pub struct MMC3 {
+	prom: Vec<u8>,
+	chr: CHR,
+
+	bank_select: u8,
+	regs: [u8; 8], // R0-R7
+
+	prg_offset: [usize; 4], // $8000, $A000, $C000, $E000
+	prg_ram_protect: u8,
+
+	irq_latch: u8,
+	irq_counter: u8,
+	irq_reload: bool,
+	irq_enable: bool,
+	irq: bool, // held until $E000 is written
+}
+
+impl MMC3 {
+	pub fn new(prom: &[u8], chr: CHR) -> MMC3 {
+		MMC3 {
+			prom: prom.to_vec(),
+			chr: chr,
+
+			bank_select: 0,
+			regs: [0, 2, 4, 5, 6, 7, 0, 1],
+
+			prg_offset: [0, 0, 0, 0],
+			// enabled at power-on, some games never write $A001
+			prg_ram_protect: PRG_RAM_ENABLE,
+
+			irq_latch: 0,
+			irq_counter: 0,
+			irq_reload: false,
+			irq_enable: false,
+			irq: false,
+		}
+	}
+
+	fn update_prg(&mut self) {
+		let banks = self.prom.len() / PROM_BANK_SIZE;
+		let r6 = self.regs[6] as usize % banks;
+		let r7 = self.regs[7] as usize % banks;
+		let banks = if self.bank_select & BANK_SELECT_PRG_MODE == 0 {
+			[r6, r7, banks - 2, banks - 1]
+		} else {
+			[banks - 2, r7, r6, banks - 1]
+		};
+		for i in 0..4 {
+			self.prg_offset[i] = banks[i] * PROM_BANK_SIZE;
+		}
+	}
+
+	fn update_chr(&mut self, ppu: &mut PPU) {
+		let r = &self.regs;
+		let banks: [u8; 8] = [
+			r[0] & 0xFE, r[0] | 0x01, r[1] & 0xFE, r[1] | 0x01,
+			r[2], r[3], r[4], r[5],
+		];
+		let inversion = if self.bank_select & BANK_SELECT_CHR_INVERSION == 0 { 0 } else { 4 };
+		for i in 0..8 {
+			let addr = ((i + inversion) % 8) * CROM_BANK_SIZE;
+			self.chr.set_bank(ppu, addr, banks[i] as usize, CROM_BANK_SIZE);
+		}
+	}
+}
+
+impl Mapper for MMC3 {
+	fn init(&mut self, ppu: &mut PPU) {
+		self.update_prg();
+		self.update_chr(ppu);
+	}
+
+	fn read(&self, addr: u16) -> u8 {
+		let bank = ((addr - 0x8000) as usize) / PROM_BANK_SIZE;
+		return self.prom[self.prg_offset[bank] + (addr as usize) % PROM_BANK_SIZE];
+	}
+
+	fn chr(&mut self) -> &mut CHR {
+		return &mut self.chr;
+	}
+
+	fn write(&mut self, addr: u16, v: u8, ppu: &mut PPU) {
+		let even = addr & 0x0001 == 0;
+		match addr {
+			0x8000 ..= 0x9FFF => {
+				if even {
+					self.bank_select = v;
+				} else {
+					self.regs[(self.bank_select & BANK_SELECT_REG_MASK) as usize] = v;
+				}
+				self.update_prg();
+				self.update_chr(ppu);
+			}
+			0xA000 ..= 0xBFFF => {
+				if even {
+					if v & MIRROR_HORIZONTAL == 0 {
+						ppu.set_mirror(Mirror::VARTICAL);
+					} else {
+						ppu.set_mirror(Mirror::HORIZONTAL);
+					}
+				} else {
+					self.prg_ram_protect = v;
+				}
+			}
+			0xC000 ..= 0xDFFF => {
+				if even {
+					self.irq_latch = v;
+				} else {
+					self.irq_counter = 0;
+					self.irq_reload = true;
+				}
+			}
+			_ => {
+				if even {
+					self.irq_enable = false;
+					self.irq = false;
+				} else {
+					self.irq_enable = true;
+				}
+			}
+		}
+	}
+
+	fn a12_rise(&mut self) {
+		if self.irq_counter == 0 || self.irq_reload {
+			self.irq_counter = self.irq_latch;
+			self.irq_reload = false;
+		} else {
+			self.irq_counter -= 1;
+		}
+
+		if self.irq_counter == 0 && self.irq_enable {
+			self.irq = true;
+		}
+	}
+
+	fn prg_ram_enabled(&self) -> bool {
+		return self.prg_ram_protect & PRG_RAM_ENABLE != 0;
+	}
+
+	fn prg_ram_writable(&self) -> bool {
+		return self.prg_ram_protect & PRG_RAM_WRITE_PROTECT == 0;
+	}
+
+	fn irq(&self) -> bool {
+		return self.irq;
+	}
+
+	fn save_state(&self, w: &mut StateWriter) {
+		w.write_u8(self.bank_select);
+		w.write_bytes(&self.regs);
+		w.write_u8(self.prg_ram_protect);
+		w.write_u8(self.irq_latch);
+		w.write_u8(self.irq_counter);
+		w.write_bool(self.irq_reload);
+		w.write_bool(self.irq_enable);
+		w.write_bool(self.irq);
+		self.chr.save_state(w);
+	}
+
+	fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		self.bank_select = r.read_u8()?;
+		r.read_bytes(&mut self.regs)?;
+		self.prg_ram_protect = r.read_u8()?;
+		self.irq_latch = r.read_u8()?;
+		self.irq_counter = r.read_u8()?;
+		self.irq_reload = r.read_bool()?;
+		self.irq_enable = r.read_bool()?;
+		self.irq = r.read_bool()?;
+		self.update_prg();
+		self.chr.load_state(r)?;
+		return Ok(());
+	}
+}
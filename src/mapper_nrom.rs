@@ -0,0 +1,47 @@
+use crate::ppu::*;
+use crate::mapper::*;
+use crate::state::*;
+use std::io;
+
+// Mapper 0
+pub struct NROM {
+	prom: Vec<u8>,
+	chr: CHR,
+}
+
+impl NROM {
+	pub fn new(prom: &[u8], chr: CHR) -> NROM {
+		NROM {
+			prom: prom.to_vec(),
+			chr: chr,
+		}
+	}
+}
+
+impl Mapper for NROM {
+	fn init(&mut self, ppu: &mut PPU) {
+		self.chr.set_bank(ppu, 0x0000, 0, 0x2000);
+	}
+
+	fn read(&self, addr: u16) -> u8 {
+		// 16k PROM is mirrored to $C000-$FFFF
+		return self.prom[(addr - 0x8000) as usize % self.prom.len()];
+	}
+
+	fn chr(&mut self) -> &mut CHR {
+		return &mut self.chr;
+	}
+
+	fn write(&mut self, _addr: u16, _v: u8, _ppu: &mut PPU) {
+		// no registers
+	}
+
+	fn save_state(&self, w: &mut StateWriter) {
+		self.chr.save_state(w);
+	}
+
+	fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		self.chr.load_state(r)?;
+		return Ok(());
+	}
+}
@@ -0,0 +1,66 @@
+use crate::ppu::*;
+use crate::mapper::*;
+use crate::state::*;
+use std::io;
+
+const PROM_BANK_SIZE: usize = 0x4000;
+
+// Mapper 2
+pub struct UxROM {
+	prom: Vec<u8>,
+	chr: CHR,
+
+	prg_offset: [usize; 2], // $8000, $C000
+}
+
+impl UxROM {
+	pub fn new(prom: &[u8], chr: CHR) -> UxROM {
+		UxROM {
+			prom: prom.to_vec(),
+			chr: chr,
+
+			prg_offset: [0, 0],
+		}
+	}
+
+	fn banks(&self) -> usize {
+		return (self.prom.len() / PROM_BANK_SIZE).max(1);
+	}
+}
+
+impl Mapper for UxROM {
+	fn init(&mut self, ppu: &mut PPU) {
+		// PRG smaller than 16k is mirrored into both windows
+		let banks = self.banks();
+		self.prg_offset = [0, (banks - 1) * PROM_BANK_SIZE];
+		self.chr.set_bank(ppu, 0x0000, 0, 0x2000);
+	}
+
+	fn read(&self, addr: u16) -> u8 {
+		let bank = ((addr - 0x8000) as usize) / PROM_BANK_SIZE;
+		return self.prom[(self.prg_offset[bank] + (addr as usize) % PROM_BANK_SIZE) % self.prom.len()];
+	}
+
+	fn chr(&mut self) -> &mut CHR {
+		return &mut self.chr;
+	}
+
+	fn write(&mut self, _addr: u16, v: u8, _ppu: &mut PPU) {
+		// switch 16k at $8000, last bank is fixed at $C000
+		let banks = self.banks();
+		self.prg_offset[0] = (v as usize % banks) * PROM_BANK_SIZE;
+	}
+
+	fn save_state(&self, w: &mut StateWriter) {
+		w.write_usize(self.prg_offset[0]);
+		w.write_usize(self.prg_offset[1]);
+		self.chr.save_state(w);
+	}
+
+	fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		self.prg_offset[0] = r.read_usize()? % self.prom.len();
+		self.prg_offset[1] = r.read_usize()? % self.prom.len();
+		self.chr.load_state(r)?;
+		return Ok(());
+	}
+}
@@ -7,171 +7,196 @@ use crate::ppu::*;
 use crate::apu::*;
 use crate::pad::*;
 use crate::io::*;
-use crate::events::*;
+use crate::mapper::*;
+use crate::mapper_nrom::*;
+use crate::state::*;
+use std::io;
+use std::time::Instant;
+use std::time::Duration;
+
+pub const WATCH_READ: u8 = 0x01;
+pub const WATCH_WRITE: u8 = 0x02;
+
+pub struct WatchHit {
+	pub addr: u16,
+	pub write: bool,
+	pub value: u8,
+}
 
 pub struct MMU {
-	mapper: u8,
+	mapper: Rc<RefCell<dyn Mapper>>,
 	wram: Vec<u8>,
-	prom: Vec<u8>,
-	crom: Vec<u8>,
+	sram: Vec<u8>,
+	sram_dirty: bool,
+
+	// debugger
+	watchpoints: Vec<(u16, u8)>, // (addr, WATCH_READ | WATCH_WRITE)
+	watch_hit: Option<WatchHit>,
+
+	// profiling
+	profile: bool,
+	prof_ppu: Duration,
+	prof_apu: Duration,
+
 	ppu: Rc<RefCell<PPU>>,
 	apu: Rc<RefCell<APU>>,
 	io: Arc<Mutex<IO>>,
-	event_queue: Arc<Mutex<EventQueue>>
+
+	dma: Option<u8>, // OAM DMA page written to $4014, run by the CPU
+
+	bus: u8,       // CPU data bus, read back from unmapped addresses (open bus)
+	ppu_latch: u8, // PPU I/O latch
 }
 
 impl MMU {
 	pub fn new(
 		ppu:Rc<RefCell<PPU>>,
 		apu:Rc<RefCell<APU>>,
-		io: Arc<Mutex<IO>>,
-		event_queue: Arc<Mutex<EventQueue>>
+		io: Arc<Mutex<IO>>
 	) -> MMU {
 		MMU {
-			mapper: 0,
+			// placeholder until a cartridge is inserted
+			mapper: Rc::new(RefCell::new(NROM::new(&[0; 0x4000], CHR::new(&[], 0)))),
 			wram: vec![0; 0x0800],
-			prom: Vec::new(),
-			crom: Vec::new(),
+			sram: vec![0; 0x2000],
+			sram_dirty: false,
+			watchpoints: Vec::new(),
+			watch_hit: None,
+			profile: false,
+			prof_ppu: Duration::new(0, 0),
+			prof_apu: Duration::new(0, 0),
 			ppu: ppu,
 			apu: apu,
 			io: io,
-			event_queue: event_queue
+			dma: None,
+			bus: 0,
+			ppu_latch: 0,
 		}
 	}
 
-	pub fn read_1byte(&mut self, addr:u16) -> u8 {
-		// TODO: address mapping
+	// One CPU cycle passes on the bus
+	//       Master          CPU      PPU    APU
+	// NTSC: 21477272.72 Hz  Base/12  Base/4 Base/12
+	pub fn tick(&mut self) {
+		if self.profile {
+			let t1 = Instant::now();
+			{
+				let mut ppu = self.ppu.borrow_mut();
+				ppu.clock();
+				ppu.clock();
+				ppu.clock();
+			}
+			let t2 = Instant::now();
+			self.apu.borrow_mut().clock();
+			let t3 = Instant::now();
+			self.mapper.borrow_mut().cpu_clock();
+			self.prof_ppu = self.prof_ppu.saturating_add(t2.duration_since(t1));
+			self.prof_apu = self.prof_apu.saturating_add(t3.duration_since(t2));
+		} else {
+			let mut ppu = self.ppu.borrow_mut();
+			ppu.clock();
+			ppu.clock();
+			ppu.clock();
+			self.apu.borrow_mut().clock();
+			self.mapper.borrow_mut().cpu_clock();
+		}
+	}
+
+	// Internal RAM and bus state back to the power-on state
+	pub fn power_on(&mut self) {
+		self.wram = vec![0; 0x0800];
+		self.dma = None;
+		self.bus = 0;
+		self.ppu_latch = 0;
+	}
 
+	pub fn set_profile(&mut self, b:bool) {
+		self.profile = b;
+	}
+
+	// Time spent in PPU and APU since the last call
+	pub fn take_profile(&mut self) -> (Duration, Duration) {
+		let ret = (self.prof_ppu, self.prof_apu);
+		self.prof_ppu = Duration::from_secs(0);
+		self.prof_apu = Duration::from_secs(0);
+		return ret;
+	}
+
+	// Interrupt lines as seen by the CPU
+	pub fn nmi_line(&self) -> bool {
+		return self.ppu.borrow().nmi_line();
+	}
+
+	// IRQ sources are wired-OR
+	pub fn irq_line(&self) -> bool {
+		return self.apu.borrow().irq() || self.mapper.borrow().irq();
+	}
+
+	pub fn read_1byte(&mut self, addr:u16) -> u8 {
 		let ret:u8;
 
 		match addr {
-			0x0000 ..= 0x07FF => {
-				ret = self.wram[addr as usize];
-			}
-			0x0800 ..= 0x0FFF => {
-				ret = self.wram[(addr - 0x0800) as usize];
-			}
-			0x1000 ..= 0x17FF => {
-				ret = self.wram[(addr - 0x1000) as usize];
-			}
-			0x1800 ..= 0x1FFF => {
-				ret = self.wram[(addr - 0x1800) as usize];
-			}
-			0x2002 => {
-				ret = self.ppu.borrow_mut().get_sr();
+			0x0000 ..= 0x1FFF => {
+				ret = self.wram[(addr & 0x07FF) as usize];
 			}
-			0x2007 => {
-				ret = self.ppu.borrow_mut().read();
+			0x2000 ..= 0x3FFF => {
+				ret = self.read_ppu(addr);
 			}
 			0x4015 => {
-				ret = self.apu.borrow_mut().get_ch_ctrl();
+				// read inside the CPU, so it doesn't drive the data bus (bit 5 is open bus)
+				let ret = self.apu.borrow_mut().get_ch_ctrl() | (self.bus & 0x20);
+				if !self.watchpoints.is_empty() {
+					self.check_watch(addr, WATCH_READ, ret);
+				}
+				return ret;
 			}
 			0x4016 => {
 				let mut io = self.io.lock().unwrap();
-				ret = io.pad.in1();
+				ret = (self.bus & 0xE0) | io.pad.in1();
 			}
 			0x4017 => {
 				let mut io = self.io.lock().unwrap();
-				ret = io.pad.in2();
+				ret = (self.bus & 0xE0) | io.pad.in2();
 			}
-			0x8000 ..= 0xFFFF => {
-				ret = self.prom[(addr - 0x8000) as usize];	
-			}
-			_ => {
-				panic!("mmu.read_1byte: unmapped address: {:x}", addr);
+			0x4000 ..= 0x5FFF => {
+				// write-only APU registers, test registers and expansion area
+				ret = self.bus;
 			}
-		}
-
-		//println!("read_1byte({:x}) -> {:x}", addr, ret);
-		return ret;
-	}
-
-	pub fn read_2bytes(&mut self, addr:u16) -> u16{
-		// TODO: address mapping
-
-		let mut ret:u16;
-
-		match addr {
-			0x0000 ..= 0x07FF => {
-				ret = self.wram[(addr as usize)] as u16;
-				ret |= (self.wram[(addr + 1) as usize] as u16) << 8;
+			0x6000 ..= 0x7FFF => {
+				match self.sram_index(addr) {
+					Some(i) if self.mapper.borrow().prg_ram_enabled() => ret = self.sram[i],
+					_ => ret = self.bus,
+				}
 			}
 			0x8000 ..= 0xFFFF => {
-				ret = self.prom[(addr - 0x8000) as usize] as u16;
-				ret |= (self.prom[(addr - 0x8000 + 1) as usize] as u16) << 8;
-			}
-			_ => {
-				ret = self.read_1byte(addr) as u16;
-				ret |= (self.read_1byte(addr + 1) as u16) << 8;
+				ret = self.mapper.borrow().read(addr);
 			}
 		}
+		self.bus = ret;
 
-		//println!("read_2bytes({:x}) -> {:x}", addr, ret);
-		return ret;
-	}
-
-	pub fn indirect(&mut self, addr: u16) -> u16 {
-		let addr = self.read_2bytes(addr);
-
-		let mut ret:u16;
-		ret = self.read_1byte(addr) as u16;
-		let addr = if addr & 0xFF == 0xFF {
-			addr & 0xFF00
-		} else {
-			addr + 1
-		};
-		ret |= (self.read_1byte(addr) as u16) << 8;
-
+		//println!("read_1byte({:x}) -> {:x}", addr, ret);
+		if !self.watchpoints.is_empty() {
+			self.check_watch(addr, WATCH_READ, ret);
+		}
 		return ret;
 	}
 
-	pub fn indirect_x(&mut self, addr: u16, x: u8) -> u16 {
-        let z:u8 = self.read_1byte(addr).wrapping_add(x);
-		let mut p:u16 = self.read_1byte(z as u16) as u16;
-		let z = z.wrapping_add(1);
-		p |= (self.read_1byte(z as u16) as u16) << 8;
-
-        	return p;
-	}
-
-	pub fn indirect_y(&mut self, addr: u16, y: u8) -> u16 {
-		let z = self.read_1byte(addr);
-
-		let mut p:u16 = self.read_1byte(z as u16) as u16;
-		let z = z.wrapping_add(1);
-		p |= (self.read_1byte(z as u16) as u16) << 8;
-		p = p.wrapping_add(y as u16);
-
-		return p;
-	}
-
 	pub fn write(&mut self, addr:u16, n:u8) {
-		// TODO: address mapping
+		if !self.watchpoints.is_empty() {
+			self.check_watch(addr, WATCH_WRITE, n);
+		}
+		self.bus = n;
+		self.store(addr, n);
+		//println!("write({:x}, {:x})", addr, n);
+	}
 
+	fn store(&mut self, addr:u16, n:u8) {
 		match addr {
-			0x0000 ..= 0x07FF => {
-				self.wram[addr as usize] = n;
-			}
-			0x2000 => {
-				self.ppu.borrow_mut().set_cr1(n);
-			}
-			0x2001 => {
-				self.ppu.borrow_mut().set_cr2(n);
-			}
-			0x2003 => {
-				self.ppu.borrow_mut().set_sprite_write_addr(n);
+			0x0000 ..= 0x1FFF => {
+				self.wram[(addr & 0x07FF) as usize] = n;
 			}
-			0x2004 => {
-				self.ppu.borrow_mut().sprite_write(n);
-			}
-			0x2005 => {
-				self.ppu.borrow_mut().set_scroll(n);
-			}
-			0x2006 => {
-				self.ppu.borrow_mut().set_write_addr(n);
-			}
-			0x2007 => {
-				self.ppu.borrow_mut().write(n);
+			0x2000 ..= 0x3FFF => {
+				self.write_ppu(addr, n);
 			}
 			0x4000 => {
 				self.apu.borrow_mut().set_sw1_cr1(n);
@@ -234,7 +259,7 @@ impl MMU {
 				self.apu.borrow_mut().set_dmc4(n);
 			}
 			0x4014 => {
-				self.start_dma(n);
+				self.dma = Some(n);
 			}
 			0x4015 => {
 				self.apu.borrow_mut().set_ch_ctrl(n);
@@ -246,62 +271,203 @@ impl MMU {
 			0x4017 => {
 				self.apu.borrow_mut().set_frame_cr(n);
 			}
-			_ => {
-				panic!("mmi.write: unmapped address: {:x}", addr);
+			0x6000 ..= 0x7FFF => {
+				let mapper = self.mapper.borrow();
+				if let Some(i) = self.sram_index(addr) {
+					if mapper.prg_ram_enabled() && mapper.prg_ram_writable() {
+						self.sram[i] = n;
+						self.sram_dirty = true;
+					}
+				}
+			}
+			0x4018 ..= 0x5FFF => {
+				// test registers and expansion area, nothing connected
+			}
+			0x8000 ..= 0xFFFF => {
+				let mut ppu = self.ppu.borrow_mut();
+				self.mapper.borrow_mut().write(addr, n, &mut ppu);
 			}
 		}
-		//println!("write({:x}, {:x})", addr, n);
 	}
 
-	pub fn push_2bytes(&mut self, addr:u16, n:u16) {
-		self.write(addr -0, (n >> 8) as u8);
-		self.write(addr -1, (n & 0x00FF) as u8);
+	// PPU registers are mirrored every 8 bytes up to $3FFF.
+	// The PPU keeps the last value written or read on its own I/O latch,
+	// which is what write-only registers and unused bits of $2002 return.
+	fn read_ppu(&mut self, addr:u16) -> u8 {
+		let mut ppu = self.ppu.borrow_mut();
+		match addr & 0x0007 {
+			2 => {
+				self.ppu_latch = (ppu.get_sr() & 0xE0) | (self.ppu_latch & 0x1F);
+			}
+			4 => {
+				self.ppu_latch = ppu.sprite_read();
+			}
+			7 => {
+				self.ppu_latch = ppu.read();
+			}
+			_ => {}
+		}
+		return self.ppu_latch;
+	}
+
+	fn write_ppu(&mut self, addr:u16, n:u8) {
+		self.ppu_latch = n;
+		let mut ppu = self.ppu.borrow_mut();
+		match addr & 0x0007 {
+			0 => ppu.set_cr1(n),
+			1 => ppu.set_cr2(n),
+			2 => {} // read only
+			3 => ppu.set_sprite_write_addr(n),
+			4 => ppu.sprite_write(n),
+			5 => ppu.set_scroll(n),
+			6 => ppu.set_write_addr(n),
+			_ => ppu.write(n),
+		}
+	}
+
+	pub fn set_mapper(&mut self, mapper: Rc<RefCell<dyn Mapper>>) {
+		let mut ppu = self.ppu.borrow_mut();
+		mapper.borrow_mut().init(&mut ppu);
+		ppu.set_mapper(Rc::clone(&mapper));
+		self.mapper = mapper;
+	}
+
+	// PRG-RAM size from the cartridge header. RAM smaller than 8k is mirrored,
+	// only the first 8k of larger RAM is mapped.
+	pub fn set_prg_ram_size(&mut self, size: usize) {
+		self.sram = vec![0; size];
+		self.sram_dirty = false;
+	}
+
+	fn sram_index(&self, addr: u16) -> Option<usize> {
+		if self.sram.is_empty() {
+			return None;
+		}
+		return Some((addr - 0x6000) as usize % self.sram.len());
+	}
+
+	pub fn get_sram(&self) -> &[u8] {
+		&self.sram
+	}
+
+	pub fn set_sram(&mut self, sram: &[u8]) {
+		let len = if sram.len() < self.sram.len() { sram.len() } else { self.sram.len() };
+		self.sram[0..len].copy_from_slice(&sram[0..len]);
+	}
+
+	// Mapping trainer to $7000-$71FF
+	pub fn set_trainer(&mut self, trainer: &[u8]) {
+		self.sram[0x1000..0x1000 + trainer.len()].copy_from_slice(trainer);
 	}
 
-	pub fn pop_2bytes(&mut self, addr: u16) -> u16 {
-		let mut ret: u16;
-		ret = self.read_1byte(addr+1) as u16;
-		ret |= (self.read_1byte(addr+2) as u16) << 8;
+	// Returns true once after PRG-RAM has been written
+	pub fn take_sram_dirty(&mut self) -> bool {
+		let ret = self.sram_dirty;
+		self.sram_dirty = false;
 		return ret;
 	}
 
-	pub fn set_mapper(&mut self, m: u8) {
-		self.mapper = m;
-		//println!("prom.mapper={}", self.mapper);
+	pub fn save_state(&self, w: &mut StateWriter) {
+		w.write_bytes(&self.wram);
+		w.write_bytes(&self.sram);
+		w.write_u8(self.bus);
+		w.write_u8(self.ppu_latch);
+		self.mapper.borrow().save_state(w);
 	}
 
-	pub fn set_PROM(&mut self, prom: &[u8]) {
-		println!("prom.len={}", prom.len());
-		self.prom = vec![0; 32768];
-		match prom.len() {
-			0x4000 => {
-				self.prom[16384..32768].copy_from_slice(prom);
+	pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		r.read_bytes(&mut self.wram)?;
+		r.read_bytes(&mut self.sram)?;
+		self.bus = r.read_u8()?;
+		self.ppu_latch = r.read_u8()?;
+		self.sram_dirty = true;
+		self.mapper.borrow_mut().load_state(r)?;
+		return Ok(());
+	}
+
+	// Page of a pending OAM DMA
+	pub fn take_dma(&mut self) -> Option<u8> {
+		return self.dma.take();
+	}
+
+	// Read for debugging. Returns what the CPU would read, without the side
+	// effects of the read ($2002 keeps vblank, $2007 doesn't advance, ...).
+	pub fn peek(&self, addr:u16) -> u8 {
+		match addr {
+			0x0000 ..= 0x1FFF => self.wram[(addr & 0x07FF) as usize],
+			0x2000 ..= 0x3FFF => {
+				let ppu = self.ppu.borrow();
+				match addr & 0x0007 {
+					2 => (ppu.peek_sr() & 0xE0) | (self.ppu_latch & 0x1F),
+					4 => ppu.sprite_read(),
+					7 => ppu.peek(),
+					_ => self.ppu_latch,
+				}
+			}
+			0x4015 => self.apu.borrow().peek_ch_ctrl() | (self.bus & 0x20),
+			0x4016 => (self.bus & 0xE0) | self.io.lock().unwrap().pad.peek(0),
+			0x4017 => (self.bus & 0xE0) | self.io.lock().unwrap().pad.peek(1),
+			0x4000 ..= 0x5FFF => self.bus,
+			0x6000 ..= 0x7FFF => match self.sram_index(addr) {
+				Some(i) if self.mapper.borrow().prg_ram_enabled() => self.sram[i],
+				_ => self.bus,
+			},
+			0x8000 ..= 0xFFFF => self.mapper.borrow().read(addr),
+		}
+	}
+
+	// Write for debugging. RAM is written directly, ignoring PRG-RAM protection.
+	// Anything else gets a normal write (PPU/APU registers, mapper registers)
+	// without triggering watchpoints. Returns false if there is no PRG-RAM.
+	pub fn poke(&mut self, addr:u16, n:u8) -> bool {
+		match addr {
+			0x0000 ..= 0x1FFF => {
+				self.wram[(addr & 0x07FF) as usize] = n;
 			}
-			0x8000 => {
-				self.prom[0..32768].copy_from_slice(prom);
+			0x6000 ..= 0x7FFF => {
+				match self.sram_index(addr) {
+					Some(i) => self.sram[i] = n,
+					None => return false,
+				}
+				self.sram_dirty = true;
 			}
 			_ => {
-				panic!("not supported prom size.");
+				self.store(addr, n);
 			}
 		}
+		return true;
 	}
 
-	pub fn set_CROM(&mut self, crom: &[u8]) {
-		self.crom = crom.to_vec();
-		let mut ppu = self.ppu.borrow_mut();
-		ppu.set_crom(crom);
-		println!("crom.len={}", self.crom.len());
+	// kind: WATCH_READ | WATCH_WRITE, 0 removes the watchpoint
+	pub fn set_watchpoint(&mut self, addr:u16, kind:u8) {
+		self.watchpoints.retain(|w| w.0 != addr);
+		if kind != 0 {
+			self.watchpoints.push((addr, kind));
+		}
 	}
 
-	fn start_dma(&mut self, n:u8) {
-		let src:u16 = (n as u16) << 8;
+	pub fn watchpoints(&self) -> &[(u16, u8)] {
+		return &self.watchpoints;
+	}
 
-		let mut ppu = self.ppu.borrow_mut();
-		let sprite_mem = ppu.get_sprite_mem();
-		sprite_mem[0..256].copy_from_slice(&self.wram[src as usize..src as usize + 256]);
+	pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+		return self.watch_hit.take();
+	}
 
-		let mut queue = self.event_queue.lock().unwrap();
-		queue.push(Event::new(EventType::DMA));
+	fn check_watch(&mut self, addr:u16, kind:u8, value:u8) {
+		if self.watch_hit.is_some() {
+			return;
+		}
+		for w in self.watchpoints.iter() {
+			if w.0 == addr && w.1 & kind != 0 {
+				self.watch_hit = Some(WatchHit {
+					addr: addr,
+					write: kind == WATCH_WRITE,
+					value: value,
+				});
+				return;
+			}
+		}
 	}
 
 	pub fn peek_02(&self) -> u8 {
@@ -0,0 +1,163 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// FCEUX movie (.fm2), text format only.
+//   header: "key value" lines
+//   input:  |commands|port0|port1|port2|
+//           port is "RLDUTSBA" for a gamepad, any char except ' ' and '.' means pressed.
+// See https://fceux.com/web/help/fm2.html
+
+pub const MOVIE_CMD_SOFT_RESET: u8 = 0x01;
+pub const MOVIE_CMD_HARD_RESET: u8 = 0x02;
+
+const PAD_CHARS: &[u8; 8] = b"RLDUTSBA";
+
+#[derive(Clone, Copy, Default)]
+pub struct MovieFrame {
+	pub commands: u8,
+	pub pads: [u8; 2], // BUTTON_* in pad.rs
+}
+
+pub struct Movie {
+	pub header: Vec<(String, String)>,
+	pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+	pub fn new(rom_filename: &str) -> Movie {
+		let header = [
+			("version", "3".to_string()),
+			("emuVersion", "22020".to_string()),
+			("rerecordCount", "0".to_string()),
+			("palFlag", "0".to_string()),
+			("romFilename", rom_filename.to_string()),
+			("guid", guid()),
+			("fourscore", "0".to_string()),
+			("microphone", "0".to_string()),
+			("port0", "1".to_string()),
+			("port1", "1".to_string()),
+			("port2", "0".to_string()),
+			("FDS", "0".to_string()),
+			("NewPPU", "0".to_string()),
+			("comment", "author rustnes".to_string()),
+		];
+		Movie {
+			header: header.iter().map(|(k, v)| (k.to_string(), v.clone())).collect(),
+			frames: Vec::new(),
+		}
+	}
+
+	pub fn load(path: &str) -> io::Result<Movie> {
+		return Movie::parse(&fs::read_to_string(path)?);
+	}
+
+	pub fn parse(text: &str) -> io::Result<Movie> {
+		let mut movie = Movie {
+			header: Vec::new(),
+			frames: Vec::new(),
+		};
+
+		for (n, line) in text.lines().enumerate() {
+			let line = line.trim_end_matches('\r');
+			if line.is_empty() {
+				continue;
+			}
+			if line.starts_with('|') {
+				movie.frames.push(parse_frame(line).ok_or_else(|| {
+					io::Error::new(io::ErrorKind::InvalidData, format!("line {}: bad input record", n + 1))
+				})?);
+			} else {
+				let (key, value) = match line.find(' ') {
+					Some(i) => (&line[..i], &line[i + 1..]),
+					None => (line, ""),
+				};
+				movie.header.push((key.to_string(), value.to_string()));
+			}
+		}
+
+		if movie.get("version") != Some("3") {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "not a fm2 version 3 movie"));
+		}
+		if movie.get("binary").map_or(false, |v| v != "0") {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "binary fm2 is not supported"));
+		}
+		if movie.get("fourscore").map_or(false, |v| v != "0") {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "fourscore is not supported"));
+		}
+		return Ok(movie);
+	}
+
+	pub fn get(&self, key: &str) -> Option<&str> {
+		return self.header.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+	}
+
+	pub fn write(&self, w: &mut impl Write) -> io::Result<()> {
+		for (key, value) in self.header.iter() {
+			writeln!(w, "{} {}", key, value)?;
+		}
+		for frame in self.frames.iter() {
+			writeln!(w, "|{}|{}|{}||", frame.commands, pad_string(frame.pads[0]), pad_string(frame.pads[1]))?;
+		}
+		return Ok(());
+	}
+
+	pub fn save(&self, path: &str) -> io::Result<()> {
+		let mut w = io::BufWriter::new(fs::File::create(path)?);
+		self.write(&mut w)?;
+		return w.flush();
+	}
+}
+
+fn parse_frame(line: &str) -> Option<MovieFrame> {
+	let fields: Vec<&str> = line.split('|').collect();
+	if fields.len() < 3 {
+		return None;
+	}
+
+	let mut frame = MovieFrame::default();
+	frame.commands = fields[1].trim().parse().ok()?;
+	for port in 0..2 {
+		if let Some(field) = fields.get(port + 2) {
+			frame.pads[port] = parse_pad(field)?;
+		}
+	}
+	return Some(frame);
+}
+
+fn parse_pad(field: &str) -> Option<u8> {
+	if field.is_empty() {
+		return Some(0); // port not connected
+	}
+	if field.len() != PAD_CHARS.len() {
+		return None;
+	}
+
+	let mut mask = 0;
+	for (i, c) in field.bytes().enumerate() {
+		if c != b' ' && c != b'.' {
+			mask |= 0x80 >> i;
+		}
+	}
+	return Some(mask);
+}
+
+fn pad_string(mask: u8) -> String {
+	let mut s = String::new();
+	for i in 0..PAD_CHARS.len() {
+		if mask & (0x80 >> i) != 0 {
+			s.push(PAD_CHARS[i] as char);
+		} else {
+			s.push('.');
+		}
+	}
+	return s;
+}
+
+fn guid() -> String {
+	let t = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+	let h = format!("{:032X}", t.wrapping_mul(0x9E3779B97F4A7C15F39CC0605CEDC835));
+	return format!("{}-{}-{}-{}-{}", &h[0..8], &h[8..12], &h[12..16], &h[16..20], &h[20..32]);
+}
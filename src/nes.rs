@@ -2,7 +2,12 @@ extern crate memmap;
 
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::fs;
 use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Instant;
@@ -13,23 +18,13 @@ use crate::cpu::*;
 use crate::mmu::*;
 use crate::ppu::*;
 use crate::apu::*;
-use crate::events::*;
-
-// NES Const
-const FLAG6_MIRROR: u8             = 0x01;
-const FLAG6_HAS_BATTARY_BACKUP: u8 = 0x02;
-const FLAG6_HAS_TRAINER: u8        = 0x04;
-const FLAG6_HAS_OWN_MIRROR: u8     = 0x80;
-const FLAG6_MAPPAER_LOW: u8        = 0xF0;
-
-const FLAG7_VS_UNISYSTEM: u8       = 0x01;
-const FLAG7_PLAYCHOICE10: u8       = 0x02;
-const FLAG7_NES_2_0: u8            = 0x0C;
-const FLAG7_MAPPER_HIGH: u8        = 0xF0;
-
-const CLOCK_DIV_CPU: i32 = 12;
-const CLOCK_DIV_PPU: i32 = 4;
-const CLOCK_DIV_APU: i32 = 12;
+use crate::mapper::*;
+use crate::cartridge::*;
+use crate::io::*;
+use crate::state::*;
+use crate::movie::*;
+use crate::rewind::*;
+use crate::trace::*;
 
 pub struct NES {
 	cpu: Rc<RefCell<CPU>>,
@@ -37,231 +32,486 @@ pub struct NES {
 	ppu: Rc<RefCell<PPU>>,
 	apu: Rc<RefCell<APU>>,
 
-	clock_cpu: i32,
-	clock_ppu: i32,
-	clock_apu: i32,
+	io: Arc<Mutex<IO>>,
+
+	rom_path: String,
+	rom_crc: u32, // save states are only loaded into the same ROM
+	cartridge: Option<Cartridge>, // kept for power cycling
+	sav_path: Option<String>,
+	current_frame: u32,
+
+	// input movie
+	movie: Option<Movie>,
+	movie_record: Option<String>, // path to write when recording
+	movie_frame: usize,
+	movie_start: u32, // PPU frame of movie frame 0
 
-	event_queue: Arc<Mutex<EventQueue>>,
+	rewind: Option<Rewind>,
+	trace: Option<Box<dyn Write>>,
 
 	profile: bool,
 
 	// profiling
 	prof_cpu: Duration,
-	prof_ppu: Duration,
-	prof_apu: Duration,
 	last_frames: u32,
 }
 
 impl NES {
-	pub fn new(cpu: Rc<RefCell<CPU>>, mmu: Rc<RefCell<MMU>>, ppu: Rc<RefCell<PPU>>, apu: Rc<RefCell<APU>>, event_queue: Arc<Mutex<EventQueue>>) -> NES {
+	pub fn new(cpu: Rc<RefCell<CPU>>, mmu: Rc<RefCell<MMU>>, ppu: Rc<RefCell<PPU>>, apu: Rc<RefCell<APU>>, io: Arc<Mutex<IO>>) -> NES {
 		NES {
 			cpu: cpu,
 			mmu: mmu,
 			ppu: ppu,
 			apu: apu,
-			clock_cpu: 0,
-			clock_ppu: 0,
-			clock_apu: 0,
-			event_queue: event_queue,
+			io: io,
+			rom_path: "".to_string(),
+			rom_crc: 0,
+			cartridge: None,
+			sav_path: None,
+			current_frame: 0,
+			movie: None,
+			movie_record: None,
+			movie_frame: 0,
+			movie_start: 0,
+			rewind: None,
+			trace: None,
 			profile: false,
 			prof_cpu: Duration::new(0, 0),
-			prof_ppu: Duration::new(0, 0),
-			prof_apu: Duration::new(0, 0),
 			last_frames: 0,
 		}
 	}
 
-	pub fn load_cartridge(&self, path:&str) {
-		let file = match File::open(path) {
-			Err(e) => panic!("cannot open {}: {}", path, e.to_string()),
-			Ok(f) => f,
-		};
+	pub fn load_cartridge(&mut self, path:&str) -> Result<(), CartridgeError> {
+		let file = File::open(path)?;
+		let data = unsafe { Mmap::map(&file)? };
+
+		let cartridge = Cartridge::parse(&data)?;
+		let battery = cartridge.header.battery;
+		self.insert_cartridge(cartridge)?;
+		self.rom_path = path.to_string();
+
+		// Battery backed PRG-RAM
+		if battery {
+			let sav_path = Path::new(path).with_extension("sav").to_string_lossy().into_owned();
+			if let Ok(sram) = fs::read(&sav_path) {
+				println!("load {}", sav_path);
+				self.mmu.borrow_mut().set_sram(&sram);
+			}
+			self.sav_path = Some(sav_path);
+		}
 
-		let cartridge = match unsafe { Mmap::map(&file)} {
-			Err(e) => panic!("mmap failed.: {}", e.to_string()),
-			Ok(m) => m,
-		};
+		return Ok(());
+	}
 
-		// check header
-		if cartridge[0] != 0x4E { panic!("not nes cartridge"); }
-		if cartridge[1] != 0x45 { panic!("not nes cartridge"); }
-		if cartridge[2] != 0x53 { panic!("not nes cartridge"); }
-		if cartridge[3] != 0x1A { panic!("not nes cartridge"); }
+	// Load a ROM image from memory. Battery backup is not persisted.
+	pub fn load_rom(&mut self, data:&[u8]) -> Result<(), CartridgeError> {
+		let cartridge = Cartridge::parse(data)?;
+		return self.insert_cartridge(cartridge);
+	}
 
-		let offset: usize = if (cartridge[5] & FLAG6_HAS_TRAINER) == 0 { 16 } else { 16 + 512 };
+	fn insert_cartridge(&mut self, cartridge:Cartridge) -> Result<(), CartridgeError> {
+		let header = &cartridge.header;
 
-		// PROM size & CROM_SIZE
-		let prom_size: usize; // [16k]
-		let crom_size: usize; // [8k]
-		prom_size = cartridge[4] as usize;
-		crom_size = cartridge[5] as usize;
+		// Nothing is kept from the previous cartridge. PRG-RAM is cleared by connect_cartridge.
+		self.rom_path = "".to_string();
+		self.sav_path = None;
 
-		let mut start = offset;
-		let mut end = offset + prom_size * 16 * 1024;
-		self.mmu.borrow_mut().set_PROM(&cartridge[start .. end]);
-		start = end;
-		end = start + crom_size *  8 * 1024;
-		self.mmu.borrow_mut().set_CROM(&cartridge[start .. end]);
+		println!("mapper={}.{}, prom.len={}, crom.len={}, nes2={}", header.mapper, header.submapper, cartridge.prom.len(), cartridge.crom.len(), header.nes2);
+		self.connect_cartridge(&cartridge)?;
+		self.rom_crc = cartridge.crc32();
+		self.cartridge = Some(cartridge);
+		return Ok(());
+	}
+
+	// Fresh mapper and PRG-RAM for the cartridge
+	fn connect_cartridge(&mut self, cartridge:&Cartridge) -> Result<(), CartridgeError> {
+		let header = &cartridge.header;
 
 		// Mirror
-		let flag6: u8 = cartridge[6];
-		if flag6 & FLAG6_MIRROR == 0 {
-			println!("Mirrir Horizontal");
-			self.ppu.borrow_mut().set_mirror(Mirror::HORIZONTAL);
+		if header.four_screen {
+			println!("Mirror Four-screen");
+			self.ppu.borrow_mut().set_cartridge_mirror(Mirror::FOUR_SCREEN);
+		} else if header.vertical_mirror {
+			println!("Mirror Vartical");
+			self.ppu.borrow_mut().set_cartridge_mirror(Mirror::VARTICAL);
 		} else {
-			println!("Mirrir Vartical");
-			self.ppu.borrow_mut().set_mirror(Mirror::VARTICAL);
+			println!("Mirror Horizontal");
+			self.ppu.borrow_mut().set_cartridge_mirror(Mirror::HORIZONTAL);
 		}
 
 		// Mapper
-		let mapper: u8;
-		mapper = (cartridge[7] & FLAG7_MAPPER_HIGH) | ((cartridge[6] & FLAG6_MAPPAER_LOW) >> 4);
+		let mapper = match new_mapper(&cartridge) {
+			None => return Err(CartridgeError::UnsupportedMapper(header.mapper)),
+			Some(m) => m,
+		};
 		self.mmu.borrow_mut().set_mapper(mapper);
+
+		// PRG-RAM, the trainer needs $7000-$71FF
+		let mut prg_ram_size = header.prg_ram_size + header.prg_nvram_size;
+		if header.trainer && prg_ram_size < 0x2000 {
+			prg_ram_size = 0x2000;
+		}
+		self.mmu.borrow_mut().set_prg_ram_size(prg_ram_size);
+
+		if header.trainer {
+			self.mmu.borrow_mut().set_trainer(&cartridge.trainer);
+		}
+
+		return Ok(());
 	}
 
-	pub fn nowait(&mut self, b:bool) {
-		let mut ppu = self.ppu.borrow_mut();
-		ppu.nowait(b);
+	// Write battery backed PRG-RAM to the .sav next to the ROM
+	pub fn save_sram(&self) {
+		if let Some(sav_path) = &self.sav_path {
+			let mmu = self.mmu.borrow();
+			if let Err(e) = fs::write(sav_path, mmu.get_sram()) {
+				println!("cannot write {}: {}", sav_path, e.to_string());
+			}
+		}
 	}
 
-	pub fn profile(&mut self, b:bool) {
-		self.profile = true;
+	pub fn save_state(&self, w: &mut impl Write) -> io::Result<()> {
+		w.write_all(&self.snapshot())
 	}
 
-	pub fn clock(&mut self) {
-		//       Master          CPU      PPU    APU
-		// NTSC: 21477272.72 Hz  Base/12  Base/4 Base/12
-
-		{
-			let mut queue = self.event_queue.lock().unwrap();
-			let evt_w = queue.pop();
-			match evt_w {
-				None => {}
-				_ => {
-					let evt = evt_w.unwrap();
-					match (evt.event_type) {
-						EventType::NMI => {
-							//println!("NMI!");
-							let mut cpu = self.cpu.borrow_mut();
-							cpu.nmi();
-						}
-						EventType::IRQ => {
-							let mut cpu = self.cpu.borrow_mut();
-							cpu.irq();
-						}
-						EventType::DMA => {
-							//println!("DMA!");
-							// Stop CPU 514 cpu-clock
-							self.clock_cpu = 514*CLOCK_DIV_CPU;
-							//println!("clock_cpu={}", self.clock_cpu);
-						}
-					}
-				}
+	pub fn load_state(&mut self, r: &mut impl Read) -> io::Result<()> {
+		let mut buf = Vec::new();
+		r.read_to_end(&mut buf)?;
+		self.restore(&buf)
+	}
+
+	// Serialize the whole machine to memory
+	pub fn snapshot(&self) -> Vec<u8> {
+		let mut w = StateWriter::new();
+		for b in STATE_MAGIC.iter() {
+			w.write_u8(*b);
+		}
+		w.write_u32(STATE_VERSION);
+		w.write_u32(self.rom_crc);
+
+		w.write_u32(self.last_frames);
+
+		self.cpu.borrow().save_state(&mut w);
+		self.ppu.borrow().save_state(&mut w);
+		self.apu.borrow().save_state(&mut w);
+		self.mmu.borrow().save_state(&mut w);
+		self.io.lock().unwrap().pad.save_state(&mut w);
+
+		return w.into_vec();
+	}
+
+	// On error the machine is left as it was
+	pub fn restore(&mut self, buf: &[u8]) -> io::Result<()> {
+		let backup = self.snapshot();
+		let ret = self.restore_unchecked(buf);
+		if ret.is_err() {
+			self.restore_unchecked(&backup).expect("cannot roll back save state");
+		} else {
+			self.movie_seek();
+		}
+		return ret;
+	}
+
+	fn restore_unchecked(&mut self, buf: &[u8]) -> io::Result<()> {
+		let mut r = StateReader::new(buf);
+		for b in STATE_MAGIC.iter() {
+			if r.read_u8()? != *b {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, "not a save state"));
 			}
 		}
-		{
-			let mut ppu = self.ppu.borrow_mut();
-			if self.profile {
-				if self.clock_ppu <= 0 {
-					let t1:Instant;
-					let t2:Instant;
-					t1 = Instant::now();
-
-					ppu.clock();
-					self.clock_ppu = CLOCK_DIV_PPU -1;
-				
-					t2 = Instant::now();
-					let d = t2.duration_since(t1);
-					self.prof_ppu = self.prof_ppu.saturating_add(d);
-				} else {
-					self.clock_ppu -= 1;
+		let version = r.read_u32()?;
+		if version != STATE_VERSION {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported save state version: {}", version)));
+		}
+		if r.read_u32()? != self.rom_crc {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "save state is for another ROM"));
+		}
+
+		self.last_frames = r.read_u32()?;
+
+		self.cpu.borrow_mut().load_state(&mut r)?;
+		self.ppu.borrow_mut().load_state(&mut r)?;
+		self.apu.borrow_mut().load_state(&mut r)?;
+		self.mmu.borrow_mut().load_state(&mut r)?;
+		self.io.lock().unwrap().pad.load_state(&mut r)?;
+
+		self.current_frame = self.ppu.borrow().frames;
+		return Ok(());
+	}
+
+	fn state_path(&self, slot: u32) -> String {
+		return Path::new(&self.rom_path).with_extension(format!("st{}", slot)).to_string_lossy().into_owned();
+	}
+
+	// Save/load requested by the frontend. Handled on frame boundaries.
+	fn handle_state_request(&mut self) {
+		let request = self.io.lock().unwrap().state_request.take();
+		match request {
+			Some(StateRequest::Save(slot)) => {
+				let path = self.state_path(slot);
+				let ret = File::create(&path).and_then(|mut f| self.save_state(&mut f));
+				match ret {
+					Ok(_) => println!("saved state {}", path),
+					Err(e) => println!("cannot save state {}: {}", path, e.to_string()),
 				}
-			} else {
-				if self.clock_ppu <= 0 {
-					ppu.clock();
-					self.clock_ppu = CLOCK_DIV_PPU -1;
-				} else {
-					self.clock_ppu -= 1;
+			}
+			Some(StateRequest::Load(slot)) => {
+				let path = self.state_path(slot);
+				let ret = File::open(&path).and_then(|mut f| self.load_state(&mut f));
+				match ret {
+					Ok(_) => println!("loaded state {}", path),
+					Err(e) => println!("cannot load state {}: {}", path, e.to_string()),
 				}
 			}
+			None => {}
 		}
+	}
 
-		{
-			let mut cpu = self.cpu.borrow_mut();
-			if self.profile {
-				if self.clock_cpu <= 0 {
-					let t1:Instant;
-					let t2:Instant;
-					t1 = Instant::now();
+	// Feed the pads from a movie, starting at the current frame
+	pub fn play_movie(&mut self, movie: Movie) {
+		self.stop_movie();
+		self.movie = Some(movie);
+		self.movie_frame = 0;
+		self.movie_start = self.ppu.borrow().frames;
+		self.movie_clock();
+	}
 
-					cpu.clock();
-					self.clock_cpu = CLOCK_DIV_CPU -1;
+	// Record the pads every frame. Written to path by stop_movie().
+	pub fn record_movie(&mut self, path: &str) {
+		self.stop_movie();
+		let rom_name = Path::new(&self.rom_path).file_stem().map_or("".to_string(), |s| s.to_string_lossy().into_owned());
+		self.movie = Some(Movie::new(&rom_name));
+		self.movie_record = Some(path.to_string());
+		self.movie_frame = 0;
+		self.movie_start = self.ppu.borrow().frames;
+		self.movie_clock();
+	}
 
-					t2 = Instant::now();
-					let d = t2.duration_since(t1);
-					self.prof_cpu = self.prof_cpu.saturating_add(d);
-				} else {
-					self.clock_cpu -= 1;
-				}
-			} else {
-				if self.clock_cpu <= 0 {
-					cpu.clock();
-					self.clock_cpu = CLOCK_DIV_CPU -1;
-				} else {
-					self.clock_cpu -= 1;
-				}
+	pub fn stop_movie(&mut self) {
+		if let (Some(movie), Some(path)) = (&self.movie, &self.movie_record) {
+			match movie.save(path) {
+				Ok(_) => println!("saved movie {} ({} frames)", path, movie.frames.len()),
+				Err(e) => println!("cannot write {}: {}", path, e.to_string()),
 			}
 		}
+		self.movie = None;
+		self.movie_record = None;
+	}
 
-		{
-			let mut apu = self.apu.borrow_mut();
-			if self.profile {
-				if self.clock_apu <= 0 {
-					let t1:Instant;
-					let t2:Instant;
-					t1 = Instant::now();
+	pub fn movie_playing(&self) -> bool {
+		return self.movie.is_some() && self.movie_record.is_none();
+	}
 
-					apu.clock();
-					self.clock_apu = CLOCK_DIV_APU -1;
+	// Called on every frame boundary. The input of the next frame is latched
+	// once, so the game and a recording see the same buttons.
+	fn input_clock(&mut self) {
+		if !self.movie_playing() {
+			self.io.lock().unwrap().pad.latch();
+		}
+		self.movie_clock();
+	}
 
-					t2 = Instant::now();
-					let d = t2.duration_since(t1);
-					self.prof_apu = self.prof_apu.saturating_add(d);
-				} else {
-					self.clock_apu -= 1;
-				}
-			} else {
-				if self.clock_apu <= 0 {
-					apu.clock();
-					self.clock_apu = CLOCK_DIV_APU -1;
+	fn movie_clock(&mut self) {
+		let movie = match &mut self.movie {
+			None => return,
+			Some(m) => m,
+		};
+
+		if self.movie_record.is_some() {
+			let io = self.io.lock().unwrap();
+			movie.frames.push(MovieFrame {
+				commands: 0,
+				pads: [io.pad.input(0), io.pad.input(1)],
+			});
+		} else {
+			if self.movie_frame >= movie.frames.len() {
+				println!("movie end ({} frames)", movie.frames.len());
+				self.movie = None;
+				return;
+			}
+			let frame = movie.frames[self.movie_frame];
+			{
+				let mut io = self.io.lock().unwrap();
+				io.pad.set_input(0, frame.pads[0]);
+				io.pad.set_input(1, frame.pads[1]);
+			}
+			if frame.commands & MOVIE_CMD_HARD_RESET != 0 {
+				self.power_cycle();
+			} else if frame.commands & MOVIE_CMD_SOFT_RESET != 0 {
+				self.reset();
+			}
+		}
+		self.movie_frame += 1;
+	}
+
+	// Follow a restored state. A recording continues from there and drops the
+	// frames after it, states from before the movie started end it.
+	fn movie_seek(&mut self) {
+		let frames = self.ppu.borrow().frames;
+		if self.movie.is_none() {
+			return;
+		}
+		if frames < self.movie_start {
+			println!("state is from before the movie started");
+			self.stop_movie();
+			return;
+		}
+
+		// the restored frame has already been fed
+		self.movie_frame = (frames - self.movie_start) as usize + 1;
+		if let (Some(movie), Some(_)) = (&mut self.movie, &self.movie_record) {
+			movie.frames.truncate(self.movie_frame);
+		}
+	}
+
+	// Keep snapshots for rewinding while IO.rewind is held
+	pub fn set_rewind(&mut self, b:bool) {
+		if b {
+			self.rewind = Some(Rewind::new());
+		} else {
+			self.rewind = None;
+		}
+	}
+
+	// Called on every frame boundary
+	fn rewind_clock(&mut self) {
+		let rewinding = self.io.lock().unwrap().rewind;
+		let snapshot = match &mut self.rewind {
+			None => return,
+			Some(r) => {
+				if rewinding {
+					r.pop()
 				} else {
-					self.clock_apu -= 1;
+					let snapshot = self.snapshot();
+					self.rewind.as_mut().unwrap().push(snapshot);
+					None
 				}
 			}
+		};
+
+		if let Some(s) = snapshot {
+			if let Err(e) = self.restore(&s) {
+				println!("rewind failed: {}", e.to_string());
+			}
+		}
+	}
+
+	// Log every instruction in nestest.log format
+	pub fn set_trace(&mut self, w: Option<Box<dyn Write>>) {
+		if let Some(t) = &mut self.trace {
+			let _ = t.flush();
+		}
+		self.trace = w;
+	}
+
+	fn trace_instruction(&mut self) {
+		let line = {
+			let cpu = self.cpu.borrow();
+			if cpu.interrupt_pending() {
+				return;
+			}
+			let ppu = self.ppu.borrow();
+			trace_line(&self.mmu.borrow(), &cpu.registers(), ppu.line(), ppu.dot(), cpu.cycles())
+		};
+		if let Some(t) = &mut self.trace {
+			if let Err(e) = writeln!(t, "{}", line) {
+				println!("trace: {}", e.to_string());
+				self.trace = None;
+			}
+		}
+	}
+
+	// Run one instruction or interrupt
+	pub fn step(&mut self) {
+		self.clock();
+	}
+
+	pub fn cpu(&self) -> &Rc<RefCell<CPU>> {
+		return &self.cpu;
+	}
+
+	pub fn mmu(&self) -> &Rc<RefCell<MMU>> {
+		return &self.mmu;
+	}
+
+	pub fn ppu(&self) -> &Rc<RefCell<PPU>> {
+		return &self.ppu;
+	}
+
+	pub fn io(&self) -> &Arc<Mutex<IO>> {
+		return &self.io;
+	}
+
+	pub fn frames(&self) -> u32 {
+		return self.ppu.borrow().frames;
+	}
+
+	pub fn set_pc(&mut self, addr:u16) {
+		self.cpu.borrow_mut().set_pc(addr);
+	}
+
+	pub fn quit(&self) -> bool {
+		return self.ppu.borrow().quit;
+	}
+
+	pub fn nowait(&mut self, b:bool) {
+		let mut ppu = self.ppu.borrow_mut();
+		ppu.nowait(b);
+	}
+
+	pub fn profile(&mut self, b:bool) {
+		self.profile = b;
+		self.mmu.borrow_mut().set_profile(b);
+	}
+
+	// Runs one CPU instruction (or interrupt). The CPU drives the bus and
+	// the PPU/APU advance with every access, see MMU::tick.
+	pub fn clock(&mut self) {
+		if self.trace.is_some() {
+			self.trace_instruction();
+		}
+
+		if self.profile {
+			let t1 = Instant::now();
+			self.cpu.borrow_mut().clock();
+			let t2 = Instant::now();
+			self.prof_cpu = self.prof_cpu.saturating_add(t2.duration_since(t1));
+		} else {
+			self.cpu.borrow_mut().clock();
+		}
+
+		if self.ppu.borrow().frames != self.current_frame {
+			self.current_frame = self.ppu.borrow().frames;
+			self.input_clock();
+			self.rewind_clock();
+			self.handle_state_request();
 		}
 
 		if self.ppu.borrow().frames >= self.last_frames + 60 {
 			if self.profile {
-				println!("prof: {}, {}, {}", self.prof_cpu.as_millis(), self.prof_ppu.as_millis(), self.prof_apu.as_millis());
+				// PPU and APU run inside the CPU's bus accesses
+				let (ppu, apu) = self.mmu.borrow_mut().take_profile();
+				let cpu = self.prof_cpu.saturating_sub(ppu).saturating_sub(apu);
+				println!("prof: {}, {}, {}", cpu.as_millis(), ppu.as_millis(), apu.as_millis());
 				self.prof_cpu = Duration::from_secs(0);
-				self.prof_ppu = Duration::from_secs(0);
-				self.prof_apu = Duration::from_secs(0);
 			}
 			self.last_frames = self.ppu.borrow().frames;
+
+			// flush PRG-RAM every 60 frames if it was written
+			let dirty = self.mmu.borrow_mut().take_sram_dirty();
+			if dirty {
+				self.save_sram();
+			}
 		}
 	}
 
 	pub fn clock_nestest(&mut self) {
-		if self.clock_cpu <= 0 {
-			self.clock();
-			let mmu = self.mmu.borrow_mut();
-			let m2 = mmu.peek_02();
-			let m3 = mmu.peek_03();
-			if m2 != 0 || m3 != 0 {
-				println!("nestest: {:02X}, {:02X}", m2, m3);
-			}
-		} else {
-			self.clock();
+		self.clock();
+		let mmu = self.mmu.borrow_mut();
+		let m2 = mmu.peek_02();
+		let m3 = mmu.peek_03();
+		if m2 != 0 || m3 != 0 {
+			println!("nestest: {:02X}, {:02X}", m2, m3);
 		}
 	}
 
@@ -273,4 +523,23 @@ impl NES {
 		ppu.reset();
 		apu.reset();
 	}
+
+	// Power off and on again. RAM, the PPU, the APU and the mapper start over,
+	// battery backed PRG-RAM is kept.
+	pub fn power_cycle(&mut self) {
+		self.ppu.borrow_mut().power_on();
+		self.apu.borrow_mut().power_on();
+		self.mmu.borrow_mut().power_on();
+
+		if let Some(cartridge) = self.cartridge.take() {
+			let sram = self.mmu.borrow().get_sram().to_vec();
+			self.connect_cartridge(&cartridge).expect("cartridge was inserted before");
+			if cartridge.header.battery {
+				self.mmu.borrow_mut().set_sram(&sram);
+			}
+			self.cartridge = Some(cartridge);
+		}
+
+		self.cpu.borrow_mut().power_on();
+	}
 }
@@ -1,3 +1,16 @@
+use std::io;
+use crate::state::*;
+
+// Button mask, in the order the buttons are shifted out of $4016/$4017
+pub const BUTTON_A: u8      = 0x01;
+pub const BUTTON_B: u8      = 0x02;
+pub const BUTTON_SELECT: u8 = 0x04;
+pub const BUTTON_START: u8  = 0x08;
+pub const BUTTON_UP: u8     = 0x10;
+pub const BUTTON_DOWN: u8   = 0x20;
+pub const BUTTON_LEFT: u8   = 0x40;
+pub const BUTTON_RIGHT: u8  = 0x80;
+
 pub struct Pad {
 	a: [u8;2],
 	b: [u8;2],
@@ -19,6 +32,8 @@ pub struct Pad {
 	down_: [u8;2],
 	left_: [u8;2],
 	right_: [u8;2],
+
+	input: [u8;2], // buttons the game sees this frame, see latch()
 }
 
 impl Pad {
@@ -44,6 +59,8 @@ impl Pad {
 			down_: [0, 0],
 			left_: [0, 0],
 			right_: [0, 0],
+
+			input: [0, 0],
 		}
 	}
 
@@ -97,6 +114,21 @@ impl Pad {
 		return ret;
 	}
 
+	// Next bit in1()/in2() would return, without shifting
+	pub fn peek(&self, n:usize) -> u8 {
+		let count = if n == 0 { self.in_count_1 } else { self.in_count_2 };
+		match count % 8 {
+			0 => self.a[n],
+			1 => self.b[n],
+			2 => self.select[n],
+			3 => self.start[n],
+			4 => self.up[n],
+			5 => self.down[n],
+			6 => self.left[n],
+			_ => self.right[n],
+		}
+	}
+
 	pub fn set_a(&mut self, n:u32, v:u8) {
 		self.a_[n as usize] = v;
 	}
@@ -129,23 +161,93 @@ impl Pad {
 		self.right_[n as usize] = v;
 	}
 
+	pub fn set_buttons(&mut self, n:u32, mask:u8) {
+		let bit = |b:u8| if mask & b != 0 { 1 } else { 0 };
+		self.set_a(n, bit(BUTTON_A));
+		self.set_b(n, bit(BUTTON_B));
+		self.set_select(n, bit(BUTTON_SELECT));
+		self.set_start(n, bit(BUTTON_START));
+		self.set_up(n, bit(BUTTON_UP));
+		self.set_down(n, bit(BUTTON_DOWN));
+		self.set_left(n, bit(BUTTON_LEFT));
+		self.set_right(n, bit(BUTTON_RIGHT));
+	}
+
+	// Buttons currently held (not latched yet)
+	pub fn buttons(&self, n:u32) -> u8 {
+		let n = n as usize;
+		let bit = |v:u8, b:u8| if v != 0 { b } else { 0 };
+		return bit(self.a_[n], BUTTON_A) | bit(self.b_[n], BUTTON_B)
+			| bit(self.select_[n], BUTTON_SELECT) | bit(self.start_[n], BUTTON_START)
+			| bit(self.up_[n], BUTTON_UP) | bit(self.down_[n], BUTTON_DOWN)
+			| bit(self.left_[n], BUTTON_LEFT) | bit(self.right_[n], BUTTON_RIGHT);
+	}
+
+	// Called on frame boundaries. The held buttons become the input of the next
+	// frame, so a button pressed mid-frame doesn't change what the game reads.
+	pub fn latch(&mut self) {
+		for n in 0..2 {
+			self.input[n] = self.buttons(n as u32);
+		}
+	}
+
+	// Input of the next frame, replacing the held buttons (movie playback)
+	pub fn set_input(&mut self, n:u32, mask:u8) {
+		self.input[n as usize] = mask;
+	}
+
+	// Buttons the game sees this frame
+	pub fn input(&self, n:u32) -> u8 {
+		return self.input[n as usize];
+	}
+
+	pub fn save_state(&self, w: &mut StateWriter) {
+		for n in 0..2 {
+			let latched = [
+				self.a[n], self.b[n], self.select[n], self.start[n],
+				self.up[n], self.down[n], self.left[n], self.right[n],
+			];
+			w.write_bytes(&latched);
+		}
+		w.write_bytes(&self.input);
+		w.write_u8(self.last_out);
+		w.write_u8(self.in_count_1);
+		w.write_u8(self.in_count_2);
+	}
+
+	pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+		for n in 0..2 {
+			let mut latched = [0; 8];
+			r.read_bytes(&mut latched)?;
+			self.a[n] = latched[0];
+			self.b[n] = latched[1];
+			self.select[n] = latched[2];
+			self.start[n] = latched[3];
+			self.up[n] = latched[4];
+			self.down[n] = latched[5];
+			self.left[n] = latched[6];
+			self.right[n] = latched[7];
+		}
+		r.read_bytes(&mut self.input)?;
+		self.last_out = r.read_u8()?;
+		self.in_count_1 = r.read_u8()? % 8;
+		self.in_count_2 = r.read_u8()? % 8;
+		return Ok(());
+	}
+
 	fn strobe(&mut self) {
-		self.a[0] = self.a_[0];
-		self.a[1] = self.a_[1];
-		self.b[0] = self.b_[0];
-		self.b[1] = self.b_[1];
-		self.select[0] = self.select_[0];
-		self.select[1] = self.select_[1];
-		self.start[0] = self.start_[0];
-		self.start[1] = self.start_[1];
-		self.up[0] = self.up_[0];
-		self.up[1] = self.up_[1];
-		self.down[0] = self.down_[0];
-		self.down[1] = self.down_[1];
-		self.left[0] = self.left_[0];
-		self.left[1] = self.left_[1];
-		self.right[0] = self.right_[0];
-		self.right[1] = self.right_[1];
+		for n in 0..2 {
+			let input = self.input[n];
+			let bit = |b:u8| if input & b != 0 { 1 } else { 0 };
+			self.a[n] = bit(BUTTON_A);
+			self.b[n] = bit(BUTTON_B);
+			self.select[n] = bit(BUTTON_SELECT);
+			self.start[n] = bit(BUTTON_START);
+			self.up[n] = bit(BUTTON_UP);
+			self.down[n] = bit(BUTTON_DOWN);
+			self.left[n] = bit(BUTTON_LEFT);
+			self.right[n] = bit(BUTTON_RIGHT);
+		}
 	}
 
 	fn reset_count(&mut self) {
@@ -1,5 +1,9 @@
-use crate::events::*;
 use crate::io::*;
+use crate::mapper::*;
+use crate::state::*;
+use std::io;
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::sync::Arc;
 use std::sync::Condvar;
 use std::sync::Mutex;
@@ -14,14 +18,22 @@ const SPRITE_PALETTE_BASE: usize = 0x3F10;
 
 /* Control Regster1 &H2000 */
 const FLAG_NMI_ON_VB: u8 = 0x80;
+const CR1_SPRITE_SIZE_MASK: u8 = 0x20; // 0: 8x8, 1: 8x16
 const CR1_BG_PATTABLE_MASK: u8 = 0x10; // 0: 0x0000, 1:0x1000
 const CR1_SP_PATTABLE_MASK: u8 = 0x08; // 0: 0x0000, 1:0x1000
 const FLAG_ADDR_INC: u8 = 0x04; // 0: +1, 1: +32
 const CR1_NAMETABLE_MASK: u8 = 0x03;
 
 /* Control Register2 &H2001 */
+const CR2_EMPHASIS_MASK: u8 = 0xE0; // Blue, Green, Red
 const CR2_FLAG_ENABLE_SPRITE: u8 = 0x10;
 const CR2_FLAG_ENABLE_BG: u8 = 0x08;
+const CR2_FLAG_SPRITE_LEFT: u8 = 0x04; // 0: sprites hidden in the left 8 pixels
+const CR2_FLAG_BG_LEFT: u8 = 0x02; // 0: background hidden in the left 8 pixels
+const CR2_FLAG_GRAYSCALE: u8 = 0x01;
+
+// Each emphasis bit darkens the other two color components
+const EMPHASIS_ATTENUATION: f32 = 0.746;
 
 /* Status Register &H2002 */
 const FLAG_VBLANK: u8 = 0x80;
@@ -29,6 +41,7 @@ const FLAG_SP_HIT: u8 = 0x40;
 const SCANLINE_SPLITE_OVER: u8 = 0x20;
 const IFLAG_VBLANK: u8 = 0x7F;
 const IFLAG_SP_HIT: u8 = 0xBF;
+const IFLAG_SPLITE_OVER: u8 = 0xDF;
 
 /* Sprite attributes */
 const SPRITE_ATTRIBUTE_BACK: u8 = 0x20;
@@ -66,12 +79,6 @@ macro_rules! CLEAR_VBLANK {
     };
 }
 
-macro_rules! get_nametable {
-    ($cr1: expr) => {
-        $cr1 & CR1_NAMETABLE_MASK
-    };
-}
-
 macro_rules! SET_SPRITE_HIT {
     ($sr: expr) => {
         $sr |= FLAG_SP_HIT;
@@ -84,6 +91,28 @@ macro_rules! CLEAR_SPRITE_HIT {
     };
 }
 
+macro_rules! SET_SPRITE_OVER {
+    ($sr: expr) => {
+        $sr |= SCANLINE_SPLITE_OVER;
+    };
+}
+
+macro_rules! CLEAR_SPRITE_OVER {
+    ($sr: expr) => {
+        $sr &= IFLAG_SPLITE_OVER;
+    };
+}
+
+macro_rules! get_sprite_height {
+    ($cr1: expr) => {
+        if ($cr1 & CR1_SPRITE_SIZE_MASK) == 0 {
+            8
+        } else {
+            16
+        }
+    };
+}
+
 macro_rules! get_bg_pattern_table_addr {
     ($cr1: expr) => {
         if ($cr1 & CR1_BG_PATTABLE_MASK) == 0 {
@@ -104,23 +133,30 @@ macro_rules! get_sprite_pattern_table_addr {
     };
 }
 
+#[allow(non_camel_case_types)]
 pub enum Mirror {
     HORIZONTAL,
     VARTICAL,
+    SINGLE_SCREEN_LOWER, // $2000
+    SINGLE_SCREEN_UPPER, // $2400
+    FOUR_SCREEN,         // extra VRAM on the cartridge
 }
 
 pub struct PPU {
     cr1: u8, // Control Register 1
     cr2: u8, // Control Register 1
     sr: u8,  // Status Register
-    scroll_y: u8,
-    scroll_x: u8,
+
+    // Internal scroll/address registers ("loopy" registers)
+    //  v, t: yyy NN YYYYY XXXXX (fine Y, nametable, coarse Y, coarse X)
+    v: u16,     // current VRAM address
+    t: u16,     // temporary VRAM address, top-left of the screen
+    fine_x: u8, // fine X scroll (0..7)
+    w: bool,    // write toggle shared by $2005 and $2006
 
     line: u32,
     line_clock: u32,
 
-    write_mode: u8, // 0 or 1
-    write_addr: u16,
     sprite_write_addr: usize,
     read_buffer: u8,
     mem: Vec<u8>,
@@ -129,38 +165,52 @@ pub struct PPU {
     sprite_id_buffer: Vec<u8>,
     sprite_buffer_len: usize,
     mirror: Mirror,
-
-    pattern_lut: Vec<u8>,
-    attr_addr_lut: Vec<usize>,
+    mapper: Option<Rc<RefCell<dyn Mapper>>>,
+    a12: bool,
+
+    // Background tile fetched in the current 8-dot cycle
+    bg_nt: u8,
+    bg_at: u8, // palette of the tile (2 bits)
+    bg_lo: u8,
+    bg_hi: u8,
+    // Background shift registers, the upper byte is the tile being drawn
+    bg_shift_lo: u16,
+    bg_shift_hi: u16,
+    at_shift_lo: u16,
+    at_shift_hi: u16,
+    // Sprite patterns fetched at dots 257..320 for the next line, 8 x [Lo, Hi]
+    sprite_pattern: Vec<u8>,
+
+    color_lut: Vec<u8>, // COLOR_TABLE for each of the 8 emphasis settings
     line_buffer: Vec<u8>,
 
     io: Arc<Mutex<IO>>,
-    event_queue: Arc<Mutex<EventQueue>>,
     vbr: Arc<(Mutex<VBR>, Condvar)>,
     last_frame_time: Instant,
     nowait: bool,
 
     pub frames: u32,
+    pub quit: bool,
 }
 
 impl PPU {
     pub fn new(
         io: Arc<Mutex<IO>>,
-        event_queue: Arc<Mutex<EventQueue>>,
         vbr: Arc<(Mutex<VBR>, Condvar)>,
     ) -> PPU {
         let mut ppu = PPU {
             cr1: 0,
             cr2: 0,
             sr: 0,
-            scroll_x: 0,
-            scroll_y: 0,
+
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
 
             line: 0,
             line_clock: 0,
 
-            write_mode: 0,
-            write_addr: 0,
             sprite_write_addr: 0,
             read_buffer: 0,
             mem: vec![0; 0x4000],
@@ -169,18 +219,29 @@ impl PPU {
             sprite_id_buffer: vec![0; 256],
             sprite_buffer_len: 0,
             mirror: Mirror::VARTICAL,
-
-            pattern_lut: vec![0; 256 * 256 * 8], // Hi * Lo * x
-            attr_addr_lut: vec![0; 32 * 32],
+            mapper: None,
+            a12: false,
+
+            bg_nt: 0,
+            bg_at: 0,
+            bg_lo: 0,
+            bg_hi: 0,
+            bg_shift_lo: 0,
+            bg_shift_hi: 0,
+            at_shift_lo: 0,
+            at_shift_hi: 0,
+            sprite_pattern: vec![0; 8 * 2],
+
+            color_lut: vec![0; 8 * 0x40 * 3],
             line_buffer: vec![0; 256 * 4], // 256 x [R, G, B, Stencil]
 
             io: io,
-            event_queue: event_queue,
             vbr: vbr,
             last_frame_time: Instant::now(),
             nowait: false,
 
             frames: 0,
+            quit: false,
         };
         ppu.generate_lut();
 
@@ -192,53 +253,349 @@ impl PPU {
         self.line_clock = 0;
     }
 
+    // Registers and VRAM back to the power-on state. The cartridge has to be
+    // connected again (set_mapper, set_cartridge_mirror).
+    pub fn power_on(&mut self) {
+        let mut ppu = PPU::new(Arc::clone(&self.io), Arc::clone(&self.vbr));
+        ppu.nowait = self.nowait;
+        ppu.last_frame_time = self.last_frame_time;
+        ppu.frames = self.frames;
+        ppu.quit = self.quit;
+        *self = ppu;
+    }
+
     pub fn nowait(&mut self, b: bool) {
         self.nowait = b;
     }
 
+    // /NMI output: low while in vblank with NMI enabled. The CPU latches the edge,
+    // so enabling NMI during vblank fires it and reading $2002 can suppress it.
+    pub fn nmi_line(&self) -> bool {
+        return (self.sr & FLAG_VBLANK) != 0 && (self.cr1 & FLAG_NMI_ON_VB) != 0;
+    }
+
+    pub fn line(&self) -> u32 {
+        return self.line;
+    }
+
+    pub fn dot(&self) -> u32 {
+        return self.line_clock;
+    }
+
     pub fn clock(&mut self) {
         if self.line == 0 && self.line_clock == 0 {
             self.frame_start();
         }
         if self.line_clock == 0 {
             //println!("PPU: CLEAR_SPRITE_HIT: {:02X}", self.sr);
-            self.buffer_sprite(self.line);
             self.line_start();
         }
 
-        self.render_bg(self.line_clock, self.line);
-        if self.cr2 & CR2_FLAG_ENABLE_SPRITE != 0 {
-            self.render_sprite(self.line_clock, self.line);
+        if self.rendering() {
+            self.fetch_bg();
+            self.fetch_sprite();
+        }
+
+        // pixels are output at dots 1..256
+        if self.line_clock >= 1 && self.line_clock <= 256 {
+            self.render_bg(self.line_clock - 1, self.line);
+            if self.cr2 & CR2_FLAG_ENABLE_SPRITE != 0 {
+                self.render_sprite(self.line_clock - 1, self.line);
+            }
+        }
+
+        if self.rendering() {
+            self.update_scroll();
         }
 
         self.line_clock += 1;
-        if self.line == 260 && self.line_clock == 1 {
-            CLEAR_SPRITE_HIT!(self.sr);
+        // vblank starts at dot 1 of line 241, the flags are cleared at dot 1
+        // of the pre-render line
+        if self.line_clock == 1 {
+            if self.line == DRAWABLE_LINES + 1 {
+                self.start_VR();
+            } else if self.line == SCAN_LINES - 1 {
+                CLEAR_VBLANK!(self.sr);
+                CLEAR_SPRITE_HIT!(self.sr);
+                CLEAR_SPRITE_OVER!(self.sr);
+            }
         }
         if self.line_clock >= CLOCKS_PAR_LINE {
             //println!("PPU: line {}", self.line);
             self.line_end(self.line);
             self.line_clock = 0;
             self.line += 1;
-            if self.line == DRAWABLE_LINES {
-                self.start_VR();
-            }
             if self.line >= SCAN_LINES {
-                CLEAR_VBLANK!(self.sr);
                 self.line = 0;
                 self.frame_end();
             }
         }
     }
 
+    // Rendering is active on the visible lines and the pre-render line
+    // while either the background or sprites are enabled.
+    fn rendering(&self) -> bool {
+        return (self.cr2 & (CR2_FLAG_ENABLE_BG | CR2_FLAG_ENABLE_SPRITE)) != 0
+            && (self.line < DRAWABLE_LINES || self.line == SCAN_LINES - 1);
+    }
+
+    // Background fetches, one memory access every 2 dots:
+    //  NT, AT, pattern Lo, pattern Hi for each tile of dots 1..256,
+    //  and the first two tiles of the next line at dots 321..336.
+    // The shift registers move every dot and are reloaded every 8 dots.
+    fn fetch_bg(&mut self) {
+        let dot = self.line_clock;
+
+        if (dot >= 2 && dot <= 257) || (dot >= 322 && dot <= 337) {
+            self.bg_shift_lo <<= 1;
+            self.bg_shift_hi <<= 1;
+            self.at_shift_lo <<= 1;
+            self.at_shift_hi <<= 1;
+        }
+
+        if ((dot >= 9 && dot <= 257) || (dot >= 329 && dot <= 337)) && (dot - 1) % 8 == 0 {
+            self.bg_shift_lo = (self.bg_shift_lo & 0xFF00) | self.bg_lo as u16;
+            self.bg_shift_hi = (self.bg_shift_hi & 0xFF00) | self.bg_hi as u16;
+            self.at_shift_lo = (self.at_shift_lo & 0xFF00) | if self.bg_at & 0x01 != 0 { 0xFF } else { 0x00 };
+            self.at_shift_hi = (self.at_shift_hi & 0xFF00) | if self.bg_at & 0x02 != 0 { 0xFF } else { 0x00 };
+        }
+
+        if !((dot >= 1 && dot <= 256) || (dot >= 321 && dot <= 336)) {
+            return;
+        }
+        match (dot - 1) % 8 {
+            0 => {
+                self.bg_nt = self.mem[self.decode_addr(0x2000 | (self.v & 0x0FFF))];
+            }
+            2 => {
+                let addr = 0x23C0 | (self.v & 0x0C00) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x07);
+                let shift = ((self.v >> 4) & 0x04) | (self.v & 0x02);
+                self.bg_at = (self.mem[self.decode_addr(addr)] >> shift) & 0x03;
+            }
+            4 => {
+                let addr = self.bg_pattern_addr();
+                self.fetch_pattern(addr);
+                self.bg_lo = self.mem[addr as usize];
+            }
+            6 => {
+                let addr = self.bg_pattern_addr() + 8;
+                self.bg_hi = self.mem[addr as usize];
+            }
+            _ => {}
+        }
+    }
+
+    fn bg_pattern_addr(&self) -> u16 {
+        let pat_base: u16 = get_bg_pattern_table_addr!(self.cr1);
+        return pat_base + ((self.bg_nt as u16) << 4) + ((self.v >> 12) & 0x07);
+    }
+
+    // Sprites for the next line are chosen at dot 257, then the patterns of
+    // 8 sprite slots are fetched at dots 257..320. Unused slots fetch tile $FF.
+    fn fetch_sprite(&mut self) {
+        let dot = self.line_clock;
+
+        if dot == 257 {
+            if self.line < DRAWABLE_LINES {
+                self.buffer_sprite(self.line + 1);
+            } else {
+                self.sprite_buffer_len = 0;
+            }
+        }
+
+        if dot < 257 || dot > 320 {
+            return;
+        }
+        let slot = ((dot - 257) / 8) as usize;
+        match (dot - 257) % 8 {
+            4 => {
+                let addr = self.sprite_pattern_addr(slot);
+                self.fetch_pattern(addr);
+                self.sprite_pattern[slot * 2 + 0] = self.sprite_pattern_byte(slot, addr);
+            }
+            6 => {
+                let addr = self.sprite_pattern_addr(slot) + 8;
+                self.sprite_pattern[slot * 2 + 1] = self.sprite_pattern_byte(slot, addr);
+            }
+            _ => {}
+        }
+    }
+
+    // 8x16 sprites take the pattern table from bit 0 of the tile number
+    // and use an even/odd pair of tiles for the top and bottom halves.
+    fn sprite_pattern_addr(&self, slot: usize) -> u16 {
+        let height: u8 = get_sprite_height!(self.cr1);
+        let (sp_y, sp_n, sp_a) = if slot < self.sprite_buffer_len {
+            (
+                self.sprite_buffer[slot * 4 + 0],
+                self.sprite_buffer[slot * 4 + 1],
+                self.sprite_buffer[slot * 4 + 2],
+            )
+        } else {
+            (self.line as u8, 0xFF, 0)
+        };
+
+        let mut v = (self.line as u8).wrapping_sub(sp_y) & (height - 1);
+        if sp_a & SPRITE_ATTRIBUTE_FLIP_V != 0 {
+            v = height - 1 - v;
+        }
+        if height == 8 {
+            let pat_base: u16 = get_sprite_pattern_table_addr!(self.cr1);
+            return pat_base + ((sp_n as u16) << 4) + v as u16;
+        }
+
+        let pat_base: u16 = if sp_n & 0x01 == 0 { 0x0000 } else { 0x1000 };
+        let tile = (sp_n & 0xFE) as u16 + if v >= 8 { 1 } else { 0 };
+        return pat_base + (tile << 4) + (v & 0x07) as u16;
+    }
+
+    // Pattern byte of a sprite slot, already flipped horizontally
+    fn sprite_pattern_byte(&self, slot: usize, addr: u16) -> u8 {
+        if slot >= self.sprite_buffer_len {
+            return 0;
+        }
+        let pat = self.mem[addr as usize];
+        if self.sprite_buffer[slot * 4 + 2] & SPRITE_ATTRIBUTE_FLIP_H != 0 {
+            return pat.reverse_bits();
+        }
+        return pat;
+    }
+
+    // PPU address decoder, returns the index in mem
+    //  $0000-$1FFF pattern tables
+    //  $2000-$2FFF nametables, $3000-$3EFF mirrors them
+    //  $3F00-$3F1F palettes, mirrored up to $3FFF
+    fn decode_addr(&self, addr: u16) -> usize {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000..=0x1FFF => {
+                return addr as usize;
+            }
+            0x2000..=0x3EFF => {
+                // calc nametable address
+                //  +-----------+-----------+
+                //  | 2 ($2800) | 3 ($2C00) |
+                //  +-----------+-----------+
+                //  | 0 ($2000) | 1 ($2400) |
+                //  +-----------+-----------+
+                let mirror_h_nt_id: [usize; 4] = [0, 0, 2, 2];
+                let mirror_v_nt_id: [usize; 4] = [0, 1, 0, 1];
+                let nametable_id = ((addr >> 10) & 0x03) as usize;
+                let mapped = match &self.mapper {
+                    Some(mapper) => mapper.borrow().nametable(nametable_id),
+                    None => None,
+                };
+                let nametable_id = match mapped {
+                    Some(id) => id & 0x03,
+                    None => match self.mirror {
+                        Mirror::VARTICAL => mirror_v_nt_id[nametable_id],
+                        Mirror::HORIZONTAL => mirror_h_nt_id[nametable_id],
+                        Mirror::SINGLE_SCREEN_LOWER => 0,
+                        Mirror::SINGLE_SCREEN_UPPER => 1,
+                        Mirror::FOUR_SCREEN => nametable_id,
+                    },
+                };
+                return 0x2000 + nametable_id * 0x400 + (addr & 0x03FF) as usize;
+            }
+            _ => {
+                // $3F10/$3F14/$3F18/$3F1C are the same as $3F00/$3F04/$3F08/$3F0C
+                let mut addr = addr & 0x001F;
+                if addr & 0x0013 == 0x0010 {
+                    addr &= 0x000F;
+                }
+                return BG_PALETTE_BASE + addr as usize;
+            }
+        }
+    }
+
+    // Scroll register updates done by the PPU during rendering
+    fn update_scroll(&mut self) {
+        match self.line_clock {
+            8..=255 if self.line_clock % 8 == 0 => self.increment_x(),
+            256 => {
+                self.increment_x();
+                self.increment_y();
+            }
+            257 => {
+                // copy horizontal bits from t
+                self.v = (self.v & !0x041F) | (self.t & 0x041F);
+            }
+            280..=304 if self.line == SCAN_LINES - 1 => {
+                // copy vertical bits from t
+                self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+            }
+            // first two tiles of the next line
+            328 | 336 => self.increment_x(),
+            _ => {}
+        }
+    }
+
+    // Coarse X increment, wrapping into the horizontally adjacent nametable
+    fn increment_x(&mut self) {
+        if (self.v & 0x001F) == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    // Fine Y increment, carrying into coarse Y and the vertically adjacent nametable.
+    // Coarse Y 30 wraps to 0 and switches nametable, 31 wraps without switching.
+    fn increment_y(&mut self) {
+        if (self.v & 0x7000) != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut y = (self.v & 0x03E0) >> 5;
+        if y == 29 {
+            y = 0;
+            self.v ^= 0x0800;
+        } else if y == 31 {
+            y = 0;
+        } else {
+            y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (y << 5);
+    }
+
+    // $2007 accesses increment v by 1 or 32, or do both the coarse X and Y
+    // increments at once while rendering.
+    fn increment_addr(&mut self) {
+        if self.rendering() {
+            self.increment_x();
+            self.increment_y();
+        } else if self.cr1 & FLAG_ADDR_INC == 0 {
+            self.v = self.v.wrapping_add(1) & 0x7FFF;
+        } else {
+            self.v = self.v.wrapping_add(32) & 0x7FFF;
+        }
+    }
+
+    // Mirroring selected by the mapper.
+    // Four-screen VRAM is wired on the cartridge, the mapper can't change it.
     pub fn set_mirror(&mut self, m: Mirror) {
+        if let Mirror::FOUR_SCREEN = self.mirror {
+            return;
+        }
+        self.mirror = m;
+    }
+
+    // Mirroring from the cartridge header, set on insert
+    pub fn set_cartridge_mirror(&mut self, m: Mirror) {
         self.mirror = m;
     }
 
+    pub fn set_mapper(&mut self, mapper: Rc<RefCell<dyn Mapper>>) {
+        self.mapper = Some(mapper);
+    }
+
     // Mapping to 0x2000
     pub fn set_cr1(&mut self, n: u8) {
         //println!("PPU: set_cr1: {:02X}", n);
         self.cr1 = n;
+        self.t = (self.t & !0x0C00) | (((n & CR1_NAMETABLE_MASK) as u16) << 10);
     }
 
     // Mapping to 0x2001
@@ -259,44 +616,55 @@ impl PPU {
         self.sprite_write_addr &= 0xFFusize;
     }
 
+    // Mapping to 0x2004 (read)
+    pub fn sprite_read(&self) -> u8 {
+        return self.sprite_mem[self.sprite_write_addr];
+    }
+
+    // $2002 without clearing vblank and the write toggle
+    pub fn peek_sr(&self) -> u8 {
+        return self.sr;
+    }
+
     pub fn get_sr(&mut self) -> u8 {
         let sr: u8 = self.sr;
 
-        self.write_mode = 0;
+        self.w = false;
         CLEAR_VBLANK!(self.sr);
 
         //println!("PPU: get_sr: {:02X}", sr);
         return sr;
     }
 
+    // Mapping to 0x2005
     pub fn set_scroll(&mut self, v: u8) {
         //println!("PPU: set_scroll: {:02X}", v);
-        if self.write_mode == 0 {
-            self.scroll_x = v;
-            self.write_mode = 1;
+        if !self.w {
+            // coarse X and fine X
+            self.t = (self.t & !0x001F) | ((v as u16) >> 3);
+            self.fine_x = v & 0x07;
         } else {
-            self.scroll_y = v;
-            self.write_mode = 0;
+            // coarse Y and fine Y
+            self.t = (self.t & !0x73E0) | (((v as u16) & 0x07) << 12) | (((v as u16) & 0xF8) << 2);
         }
+        self.w = !self.w;
     }
 
+    // Mapping to 0x2006
     pub fn set_write_addr(&mut self, v: u8) {
-        if self.write_mode == 0 {
-            self.write_addr &= 0x00FF;
-            self.write_addr |= (v as u16) << 8;
-
-            self.write_mode = 1;
+        if !self.w {
+            // bit 14 is cleared by the first write
+            self.t = (self.t & 0x00FF) | (((v as u16) & 0x3F) << 8);
         } else {
-            self.write_addr &= 0xFF00;
-            self.write_addr |= (v as u16);
-
-            self.write_mode = 0;
+            self.t = (self.t & 0xFF00) | (v as u16);
+            self.v = self.t;
         }
+        self.w = !self.w;
     }
 
     // Mapping to 0x2007
     pub fn write(&mut self, v: u8) {
-        let addr = self.write_addr & 0x3FFF;
+        let addr = self.v & 0x3FFF;
         let mut v = v;
 
         // background pallet or sprite pallet
@@ -304,48 +672,50 @@ impl PPU {
             v &= 0x3F;
         }
 
-        self.mem[addr as usize] = v;
-
-        // Increment write address
-        if self.cr1 & FLAG_ADDR_INC == 0 {
-            self.write_addr += 1;
-        } else {
-            self.write_addr += 32;
+        // pattern table is cartridge CHR-ROM/RAM
+        match &self.mapper {
+            Some(mapper) if addr < 0x2000 => {
+                mapper.borrow_mut().write_chr(addr, v, &mut self.mem[0..0x2000]);
+            }
+            _ => {
+                let index = self.decode_addr(addr);
+                self.mem[index] = v;
+            }
         }
+
+        self.increment_addr();
     }
 
     // Mapping to 0x2007
     pub fn read(&mut self) -> u8 {
+        let addr = self.v & 0x3FFF;
         let ret: u8;
-        match self.write_addr {
-            0..=0x3EFF => {
-                ret = self.read_buffer;
-                self.read_buffer = self.mem[self.write_addr as usize];
-            }
-            0x3F00..=0x3FFF => {
-                ret = self.mem[self.write_addr as usize];
-            }
-            _ => {
-                panic!("ppu.read: unmapped address: {:x}", self.write_addr);
-            }
-        }
-
-        // Increment write address
-        if self.cr1 & FLAG_ADDR_INC == 0 {
-            self.write_addr += 1;
+        if addr < 0x3F00 {
+            ret = self.read_buffer;
+            self.read_buffer = self.mem[self.decode_addr(addr)];
         } else {
-            self.write_addr += 32;
+            // palettes are returned directly, the buffer gets the nametable below
+            ret = self.mem[self.decode_addr(addr)];
+            self.read_buffer = self.mem[self.decode_addr(addr - 0x1000)];
         }
 
+        self.increment_addr();
+
         return ret;
     }
 
+    // What a read of $2007 would return, without touching the buffer and v
+    pub fn peek(&self) -> u8 {
+        let addr = self.v & 0x3FFF;
+        if addr < 0x3F00 {
+            return self.read_buffer;
+        } else {
+            return self.mem[self.decode_addr(addr)];
+        }
+    }
+
     fn start_VR(&mut self) {
         SET_VBLANK!(self.sr);
-        if (self.cr1 & FLAG_NMI_ON_VB) != 0 {
-            let mut queue = self.event_queue.lock().unwrap();
-            queue.push(Event::new(EventType::NMI));
-        }
 
         //		let (vbr, cond) = &*self.vbr;
         //		let mut vbr = vbr.lock().unwrap();
@@ -354,10 +724,8 @@ impl PPU {
     }
 
     fn line_start(&mut self) {
-        let col = self.mem[SPRITE_PALETTE_BASE];
-        let r = COLOR_TABLE[(col * 3 + 0) as usize];
-        let g = COLOR_TABLE[(col * 3 + 1) as usize];
-        let b = COLOR_TABLE[(col * 3 + 2) as usize];
+        let col = self.mem[BG_PALETTE_BASE];
+        let (r, g, b) = self.get_color(col);
         let s = 0;
 
         for x in 0..256 {
@@ -386,10 +754,13 @@ impl PPU {
     }
 
     fn frame_end(&mut self) {
-        if (!self.nowait) {
+        {
             let (vbr, cond) = &*self.vbr;
             let mut vbr = vbr.lock().unwrap();
-            cond.wait(vbr).unwrap();
+            if !self.nowait && !vbr.quit {
+                vbr = cond.wait(vbr).unwrap();
+            }
+            self.quit = vbr.quit;
         }
 
         let t = Instant::now();
@@ -402,60 +773,19 @@ impl PPU {
         if x >= 256 || y >= 240 {
             return;
         }
-
-        // calc nametable id
-        let nametable_id = get_nametable!(self.cr1);
-        let mut scroll_x: u16 = self.scroll_x as u16;
-        let mut scroll_y: u16 = self.scroll_y as u16;
-        if nametable_id == 1 || nametable_id == 3 {
-            scroll_x += 256;
-        }
-        if nametable_id == 2 || nametable_id == 3 {
-            scroll_y += 240;
+        // the shift registers only run while rendering is enabled
+        if !self.rendering() || (self.cr2 & CR2_FLAG_ENABLE_BG) == 0 {
+            return;
         }
-
-        let xx: u32 = x as u32 + scroll_x as u32;
-        let yy: u32 = y as u32 + scroll_y as u32;
-        let xx = xx % 512;
-        let yy = yy % 480;
-
-        let mut nametable_id = if xx >= 256 { 1 } else { 0 };
-        nametable_id = nametable_id | (if yy >= 240 { 2 } else { 0 });
-
-        let mirror_h_nt_id: [u8; 4] = [0, 0, 2, 2];
-        let mirror_v_nt_id: [u8; 4] = [0, 1, 0, 1];
-        match self.mirror {
-            Mirror::VARTICAL => {
-                nametable_id = mirror_v_nt_id[nametable_id as usize];
-            }
-            Mirror::HORIZONTAL => {
-                nametable_id = mirror_h_nt_id[nametable_id as usize];
-            }
-            _ => {}
+        if x < 8 && (self.cr2 & CR2_FLAG_BG_LEFT) == 0 {
+            return;
         }
 
-        // calc nametable address
-        //  +-----------+-----------+
-        //  | 2 ($2800) | 3 ($2C00) |
-        //  +-----------+-----------+
-        //  | 0 ($2000) | 1 ($2400) |
-        //  +-----------+-----------+
-        let nametable_base: [u32; 4] = [0x2000, 0x2400, 0x2800, 0x2C00];
-        let u: u32 = (xx / 8) % 32; // [0 .. 32]
-        let v: u32 = (yy / 8) % 30; // [0 .. 30]
-        let addr: u32 = nametable_base[nametable_id as usize] + v * 32 + u;
-        let pat_id: u8 = self.mem[addr as usize]; // pattern id [0..255]
-
-        let pat_base: u16 = get_bg_pattern_table_addr!(self.cr1);
-        let pat_addr: u16 = pat_base + ((pat_id as u16) << 4);
-        let pat_addr_lo: u16 = pat_addr + (yy % 8) as u16;
-        let pat_addr_hi: u16 = pat_addr_lo + 8;
-        let pat_lo = self.mem[pat_addr_lo as usize];
-        let pat_hi = self.mem[pat_addr_hi as usize];
-        let pat = self.pattern_lut
-            [(((pat_hi as usize) * 256 + (pat_lo as usize)) * 8 + (xx as usize) % 8) as usize];
-        let attribute_table_base: [usize; 4] = [0x23C0, 0x27C0, 0x2BC0, 0x2FC0];
-        let (r, g, b) = self.get_color(nametable_id, u, v, pat);
+        let bit = 15 - self.fine_x as u16;
+        let pat = ((((self.bg_shift_hi >> bit) & 0x01) << 1) | ((self.bg_shift_lo >> bit) & 0x01)) as u8;
+        let attr = ((((self.at_shift_hi >> bit) & 0x01) << 1) | ((self.at_shift_lo >> bit) & 0x01)) as u8;
+        let col = self.mem[BG_PALETTE_BASE + (attr << 2 | pat) as usize]; // [0..3F]
+        let (r, g, b) = self.get_color(col);
 
         if pat != 0 {
             let stencil = self.line_buffer[(x * 4 + 3) as usize];
@@ -468,42 +798,66 @@ impl PPU {
         }
     }
 
+    // Sprite evaluation: the first 8 sprites on line y in OAM order.
     fn buffer_sprite(&mut self, y: u32) {
-        self.sprite_buffer_len = 0;
-        for sprite_id in 0..64 {
-            let sp_y = self.sprite_mem[(sprite_id * 4 + 0) as usize];
-            let sp_n = self.sprite_mem[(sprite_id * 4 + 1) as usize];
-            let sp_a = self.sprite_mem[(sprite_id * 4 + 2) as usize];
-            let sp_x = self.sprite_mem[(sprite_id * 4 + 3) as usize];
+        let height: u32 = get_sprite_height!(self.cr1);
+        let in_range = |sp_y: u8| y >= (sp_y as u32) + 1 && y < (sp_y as u32) + 1 + height;
 
-            if y < (sp_y as u32) + 1 || y >= (sp_y as u32) + 1 + 8 {
+        self.sprite_buffer_len = 0;
+        let mut n = 0;
+        while n < 64 {
+            let sp_y = self.sprite_mem[n * 4 + 0];
+            let sp_n = self.sprite_mem[n * 4 + 1];
+            let sp_a = self.sprite_mem[n * 4 + 2];
+            let sp_x = self.sprite_mem[n * 4 + 3];
+            n += 1;
+
+            if !in_range(sp_y) {
                 continue;
             }
             self.sprite_buffer[self.sprite_buffer_len * 4 + 0] = sp_y;
             self.sprite_buffer[self.sprite_buffer_len * 4 + 1] = sp_n;
             self.sprite_buffer[self.sprite_buffer_len * 4 + 2] = sp_a;
             self.sprite_buffer[self.sprite_buffer_len * 4 + 3] = sp_x;
-            self.sprite_id_buffer[self.sprite_buffer_len] = sprite_id;
+            self.sprite_id_buffer[self.sprite_buffer_len] = (n - 1) as u8;
 
             self.sprite_buffer_len += 1;
-            if (self.sprite_buffer_len == 7) {
+            if self.sprite_buffer_len == 8 {
+                break;
+            }
+        }
+
+        // Overflow check for the rest of OAM. The hardware increments the byte
+        // offset along with the sprite index, so it compares tile numbers,
+        // attributes and X positions as Y coordinates (false hits and misses).
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.sprite_mem[n * 4 + m]) {
+                SET_SPRITE_OVER!(self.sr);
                 break;
             }
+            n += 1;
+            m = (m + 1) & 0x03;
         }
     }
 
+    // Sprite/background multiplexing, done after render_bg for the same pixel.
+    // The first opaque sprite in OAM order wins over the other sprites even
+    // when it is behind the background, then its priority bit picks between
+    // it and an opaque background pixel.
     fn render_sprite(&mut self, x: u32, y: u32) {
-        if (y >= 239) {
+        if x >= 256 || y >= DRAWABLE_LINES {
             return;
         }
-        if (x >= 255) {
+        if x < 8 && (self.cr2 & CR2_FLAG_SPRITE_LEFT) == 0 {
             return;
         }
 
-        for buffer_id in (0..self.sprite_buffer_len).rev() {
+        let stencil = self.line_buffer[(x * 4 + 3) as usize];
+        let bg_opaque = stencil == 2;
+
+        for buffer_id in 0..self.sprite_buffer_len {
             let sprite_id: u8 = self.sprite_id_buffer[buffer_id];
-            let sp_y = self.sprite_buffer[(buffer_id * 4 + 0) as usize];
-            let sp_n = self.sprite_buffer[(buffer_id * 4 + 1) as usize];
             let sp_a = self.sprite_buffer[(buffer_id * 4 + 2) as usize];
             let sp_x = self.sprite_buffer[(buffer_id * 4 + 3) as usize];
 
@@ -511,130 +865,158 @@ impl PPU {
                 continue;
             }
 
-            let mut u = (x as u8).wrapping_sub(sp_x);
-            if sp_a & SPRITE_ATTRIBUTE_FLIP_H != 0 {
-                u = 7 - u;
-            }
-            let mut v = (y as u8).wrapping_sub(sp_y).wrapping_sub(1);
-            if sp_a & SPRITE_ATTRIBUTE_FLIP_V != 0 {
-                v = 7 - v;
-            }
-            let pat_base: u16 = get_sprite_pattern_table_addr!(self.cr1);
-            let pat_addr: u16 = pat_base + ((sp_n as u16) << 4);
-            let pat_addr_lo: u16 = pat_addr + v as u16;
-            let pat_addr_hi: u16 = pat_addr_lo + 8;
-            let pat_lo = self.mem[pat_addr_lo as usize];
-            let pat_hi = self.mem[pat_addr_hi as usize];
-            let pat = self.pattern_lut
-                [(((pat_hi as usize) * 256 + (pat_lo as usize)) * 8 + (u as usize) % 8) as usize];
+            let u = 7 - (x as u8).wrapping_sub(sp_x);
+            let pat_lo = self.sprite_pattern[buffer_id * 2 + 0];
+            let pat_hi = self.sprite_pattern[buffer_id * 2 + 1];
+            let pat = (((pat_hi >> u) & 0x01) << 1) | ((pat_lo >> u) & 0x01);
             if pat == 0 {
                 continue;
             }
-            let pat = pat | (sp_a & 0x03) << 2;
-            let col = self.mem[SPRITE_PALETTE_BASE + pat as usize]; // [0..3F]
-            let r = COLOR_TABLE[(col * 3 + 0) as usize];
-            let g = COLOR_TABLE[(col * 3 + 1) as usize];
-            let b = COLOR_TABLE[(col * 3 + 2) as usize];
 
-            let stencil = self.line_buffer[(x * 4 + 3) as usize];
-            if sp_a & SPRITE_ATTRIBUTE_BACK != 0 {
-                if stencil < 1 {
-                    self.line_buffer[(x * 4 + 0) as usize] = r;
-                    self.line_buffer[(x * 4 + 1) as usize] = g;
-                    self.line_buffer[(x * 4 + 2) as usize] = b;
-                    self.line_buffer[(x * 4 + 3) as usize] = 1;
-                }
-            } else {
-                self.line_buffer[(x * 4 + 0) as usize] = r;
-                self.line_buffer[(x * 4 + 1) as usize] = g;
-                self.line_buffer[(x * 4 + 2) as usize] = b;
-                self.line_buffer[(x * 4 + 3) as usize] = 3;
+            // sprite 0 hit: opaque sprite 0 over opaque background, never at x=255
+            if sprite_id == 0 && bg_opaque && x != 255 {
+                SET_SPRITE_HIT!(self.sr);
             }
 
-            let stencil = self.line_buffer[(x * 4 + 3) as usize];
-            if sprite_id == 0 && (self.cr2 & CR2_FLAG_ENABLE_BG) != 0 && stencil != 0 {
-                SET_SPRITE_HIT!(self.sr);
+            if sp_a & SPRITE_ATTRIBUTE_BACK != 0 && bg_opaque {
+                return;
             }
+
+            let pat = pat | (sp_a & 0x03) << 2;
+            let col = self.mem[SPRITE_PALETTE_BASE + pat as usize]; // [0..3F]
+            let (r, g, b) = self.get_color(col);
+            self.line_buffer[(x * 4 + 0) as usize] = r;
+            self.line_buffer[(x * 4 + 1) as usize] = g;
+            self.line_buffer[(x * 4 + 2) as usize] = b;
+            self.line_buffer[(x * 4 + 3) as usize] = if sp_a & SPRITE_ATTRIBUTE_BACK != 0 { 1 } else { 3 };
+            return;
         }
     }
 
-    pub fn get_sprite_mem(&mut self) -> &mut Vec<u8> {
-        &mut self.sprite_mem
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.cr1);
+        w.write_u8(self.cr2);
+        w.write_u8(self.sr);
+        w.write_u16(self.v);
+        w.write_u16(self.t);
+        w.write_u8(self.fine_x);
+        w.write_bool(self.w);
+        w.write_u32(self.line);
+        w.write_u32(self.line_clock);
+        w.write_usize(self.sprite_write_addr);
+        w.write_u8(self.read_buffer);
+        w.write_bytes(&self.mem);
+        w.write_bytes(&self.sprite_mem);
+        w.write_bytes(&self.sprite_buffer);
+        w.write_bytes(&self.sprite_id_buffer);
+        w.write_usize(self.sprite_buffer_len);
+        w.write_u8(match self.mirror {
+            Mirror::HORIZONTAL => 0,
+            Mirror::VARTICAL => 1,
+            Mirror::SINGLE_SCREEN_LOWER => 2,
+            Mirror::SINGLE_SCREEN_UPPER => 3,
+            Mirror::FOUR_SCREEN => 4,
+        });
+        w.write_bool(self.a12);
+        w.write_u8(self.bg_nt);
+        w.write_u8(self.bg_at);
+        w.write_u8(self.bg_lo);
+        w.write_u8(self.bg_hi);
+        w.write_u16(self.bg_shift_lo);
+        w.write_u16(self.bg_shift_hi);
+        w.write_u16(self.at_shift_lo);
+        w.write_u16(self.at_shift_hi);
+        w.write_bytes(&self.sprite_pattern);
+        w.write_bytes(&self.line_buffer);
+        w.write_u32(self.frames);
     }
 
-    pub fn set_crom(&mut self, crom: &[u8]) {
-        let len: usize = if crom.len() < 0x2000 {
-            crom.len()
-        } else {
-            0x2000
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.cr1 = r.read_u8()?;
+        self.cr2 = r.read_u8()?;
+        self.sr = r.read_u8()?;
+        self.v = r.read_u16()? & 0x7FFF;
+        self.t = r.read_u16()? & 0x7FFF;
+        self.fine_x = r.read_u8()? & 0x07;
+        self.w = r.read_bool()?;
+        self.line = r.read_u32()?;
+        self.line_clock = r.read_u32()?;
+        self.sprite_write_addr = r.read_usize()? & 0xFF;
+        self.read_buffer = r.read_u8()?;
+        r.read_bytes(&mut self.mem)?;
+        r.read_bytes(&mut self.sprite_mem)?;
+        r.read_bytes(&mut self.sprite_buffer)?;
+        r.read_bytes(&mut self.sprite_id_buffer)?;
+        self.sprite_buffer_len = r.read_usize()?.min(8);
+        self.mirror = match r.read_u8()? {
+            0 => Mirror::HORIZONTAL,
+            1 => Mirror::VARTICAL,
+            2 => Mirror::SINGLE_SCREEN_LOWER,
+            3 => Mirror::SINGLE_SCREEN_UPPER,
+            _ => Mirror::FOUR_SCREEN,
         };
-        self.mem[0..len].copy_from_slice(crom);
-    }
-
-    fn get_color(&self, nametable_id: u8, u: u32, v: u32, pat: u8) -> (u8, u8, u8) {
-        let attr_addr = self.attr_addr_lut[(v * 32 + u) as usize];
-        let attribute_table_base: [usize; 4] = [0x23C0, 0x27C0, 0x2BC0, 0x2FC0];
-        let attr_addr = attribute_table_base[nametable_id as usize] + attr_addr;
-        let mut attr = self.mem[attr_addr];
-
-        // 01|23
-        // 45|67
-        // --+--
-        // 89|AB
-        // CD|EF
-        let n = (v % 4) * 4 + u % 4; // [0..16]
-        match n {
-            0 | 1 | 4 | 5 => {
-                attr <<= 2;
-            }
-            2 | 3 | 6 | 7 => {
-                // NOP
-                //attr >>= 2;
-            }
-            8 | 9 | 12 | 13 => {
-                attr >>= 2;
-            }
-            10 | 11 | 14 | 15 => {
-                attr >>= 4;
+        self.a12 = r.read_bool()?;
+        self.bg_nt = r.read_u8()?;
+        self.bg_at = r.read_u8()?;
+        self.bg_lo = r.read_u8()?;
+        self.bg_hi = r.read_u8()?;
+        self.bg_shift_lo = r.read_u16()?;
+        self.bg_shift_hi = r.read_u16()?;
+        self.at_shift_lo = r.read_u16()?;
+        self.at_shift_hi = r.read_u16()?;
+        r.read_bytes(&mut self.sprite_pattern)?;
+        r.read_bytes(&mut self.line_buffer)?;
+        self.frames = r.read_u32()?;
+        return Ok(());
+    }
+
+    // Tracks the A12 address line so that the mapper can count scanlines (MMC3).
+    // Only pattern fetches are observed, so the nametable fetches in between
+    // don't produce extra edges (MMC3 filters those out on hardware).
+    fn fetch_pattern(&mut self, addr: u16) {
+        if self.cr2 & (CR2_FLAG_ENABLE_BG | CR2_FLAG_ENABLE_SPRITE) == 0 {
+            return;
+        }
+
+        let a12 = addr & 0x1000 != 0;
+        if a12 && !self.a12 {
+            if let Some(mapper) = &self.mapper {
+                mapper.borrow_mut().a12_rise();
             }
-            _ => {}
         }
-        attr &= 0x0C;
+        self.a12 = a12;
+    }
 
-        let pat = pat | attr;
-        let col = self.mem[BG_PALETTE_BASE + pat as usize]; // [0..3F]
-        let r = COLOR_TABLE[(col * 3 + 0) as usize];
-        let g = COLOR_TABLE[(col * 3 + 1) as usize];
-        let b = COLOR_TABLE[(col * 3 + 2) as usize];
+    // Called by mappers on CHR bank switching
+    pub fn set_chr_bank(&mut self, addr: usize, bank: &[u8]) {
+        self.mem[addr..addr + bank.len()].copy_from_slice(bank);
+    }
 
-        return (r, g, b);
+    // Palette entry to RGB with the grayscale and emphasis bits of cr2 applied
+    fn get_color(&self, col: u8) -> (u8, u8, u8) {
+        let mut col = col & 0x3F;
+        if self.cr2 & CR2_FLAG_GRAYSCALE != 0 {
+            col &= 0x30;
+        }
+        let emphasis = ((self.cr2 & CR2_EMPHASIS_MASK) >> 5) as usize;
+        let index = (emphasis * 0x40 + col as usize) * 3;
+        return (self.color_lut[index + 0], self.color_lut[index + 1], self.color_lut[index + 2]);
     }
 
     fn generate_lut(&mut self) {
-        // pattern table lut
-        for hi in 0..=255 {
-            for lo in 0..=255 {
-                for x in 0..8 {
-                    let pat_hi: u8 = (hi >> (7 - x)) & 0x01;
-                    let pat_lo: u8 = (lo >> (7 - x)) & 0x01;
-                    let pat: u8 = pat_hi << 1 | pat_lo;
-
-                    let mut index: usize = hi as usize;
-                    index *= 256;
-                    index += lo as usize;
-                    index *= 8;
-                    index += x as usize;
-
-                    self.pattern_lut[index] = pat;
+        // color lut for each emphasis setting (bit 0: Red, 1: Green, 2: Blue)
+        for emphasis in 0..8 {
+            for col in 0..0x40 {
+                for c in 0..3 {
+                    let mut v = COLOR_TABLE[col * 3 + c] as f32;
+                    for e in 0..3 {
+                        if emphasis & (1 << e) != 0 && e != c {
+                            v *= EMPHASIS_ATTENUATION;
+                        }
+                    }
+                    self.color_lut[(emphasis * 0x40 + col) * 3 + c] = v as u8;
                 }
             }
         }
-
-        // attribute table lut
-        for v in 0..32 {
-            for u in 0..32 {
-                self.attr_addr_lut[v * 32 + u] = (v / 4) * 8 + u / 4;
-            }
-        }
     }
 }
@@ -1,7 +1,7 @@
 extern crate gl;
 extern crate sdl2;
 
-use crate::io::*;
+use rustnes::io::*;
 use gl::types::GLuint;
 use sdl2::audio::*;
 use sdl2::event::Event;
@@ -44,6 +44,8 @@ pub struct Renderer {
 
     window_width: u32,
     window_height: u32,
+
+    state_slot: u32,
 }
 
 impl AudioCallback for AudioRenderer {
@@ -155,6 +157,8 @@ impl Renderer {
 
             window_width: 0,
             window_height: 0,
+
+            state_slot: 0,
         };
 
         ret.init_gl();
@@ -323,6 +327,48 @@ impl Renderer {
                         self.io.lock().unwrap().pad.set_right(0, 0);
                     }
 
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F12),
+                        ..
+                    } => {
+                        self.io.lock().unwrap().debug_break = true;
+                    }
+
+                    // Rewind while held
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Backspace),
+                        ..
+                    } => {
+                        self.io.lock().unwrap().rewind = true;
+                    }
+                    Event::KeyUp {
+                        keycode: Some(Keycode::Backspace),
+                        ..
+                    } => {
+                        self.io.lock().unwrap().rewind = false;
+                    }
+
+                    // Save states: 0-9 selects slot, F5 saves, F7 loads
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F5),
+                        ..
+                    } => {
+                        self.io.lock().unwrap().state_request = Some(StateRequest::Save(self.state_slot));
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F7),
+                        ..
+                    } => {
+                        self.io.lock().unwrap().state_request = Some(StateRequest::Load(self.state_slot));
+                    }
+                    Event::KeyDown {
+                        keycode: Some(key),
+                        ..
+                    } if key as i32 >= Keycode::Num0 as i32 && key as i32 <= Keycode::Num9 as i32 => {
+                        self.state_slot = (key as i32 - Keycode::Num0 as i32) as u32;
+                        println!("state slot {}", self.state_slot);
+                    }
+
                     Event::Window {
                         win_event: Resized, ..
                     } => {
@@ -0,0 +1,107 @@
+use crate::ringbuffer::*;
+
+// Rewind buffer
+//   The newest snapshot is held as is. Older ones are kept in the ring as
+//   reverse deltas (newer XOR older, zero runs compressed), so each pop
+//   rebuilds the previous snapshot from the one after it.
+//   The oldest delta is discarded first, which never breaks the chain.
+//   A snapshot is taken every frame and one is popped per frame, so rewinding
+//   plays back at normal speed.
+
+const REWIND_CAPACITY: usize = 3600; // 1 minute at 60fps
+
+const DELTA_RAW: u8 = 0; // snapshot size changed, stored as is
+const DELTA_XOR: u8 = 1;
+
+pub struct Rewind {
+	ring: RingBuffer<Vec<u8>>,
+	current: Option<Vec<u8>>,
+}
+
+impl Rewind {
+	pub fn new() -> Rewind {
+		Rewind {
+			ring: RingBuffer::new(REWIND_CAPACITY, Vec::new()),
+			current: None,
+		}
+	}
+
+	pub fn push(&mut self, snapshot: Vec<u8>) {
+		if let Some(current) = self.current.take() {
+			self.ring.write_overwrite(encode(&snapshot, &current));
+		}
+		self.current = Some(snapshot);
+	}
+
+	// Newest snapshot, going one step back
+	pub fn pop(&mut self) -> Option<Vec<u8>> {
+		let ret = self.current.take();
+		if let (Some(newer), Some(delta)) = (&ret, self.ring.read_back()) {
+			self.current = Some(decode(newer, &delta));
+		}
+		return ret;
+	}
+
+	pub fn clear(&mut self) {
+		self.ring.clear();
+		self.current = None;
+	}
+}
+
+// Delta that turns newer into older
+//   DELTA_XOR, then repeated: u32 zero run, u32 literal length, literal bytes
+fn encode(newer: &[u8], older: &[u8]) -> Vec<u8> {
+	let mut ret = Vec::new();
+	if newer.len() != older.len() {
+		ret.push(DELTA_RAW);
+		ret.extend_from_slice(older);
+		return ret;
+	}
+
+	ret.push(DELTA_XOR);
+	let mut i = 0;
+	while i < older.len() {
+		let start = i;
+		while i < older.len() && newer[i] == older[i] {
+			i += 1;
+		}
+		let zeros = i - start;
+
+		let start = i;
+		while i < older.len() && newer[i] != older[i] {
+			i += 1;
+		}
+		ret.extend_from_slice(&(zeros as u32).to_le_bytes());
+		ret.extend_from_slice(&((i - start) as u32).to_le_bytes());
+		for j in start..i {
+			ret.push(newer[j] ^ older[j]);
+		}
+	}
+	return ret;
+}
+
+fn decode(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+	if delta[0] == DELTA_RAW {
+		return delta[1..].to_vec();
+	}
+
+	let mut ret = newer.to_vec();
+	let mut pos = 1;
+	let mut i = 0;
+	let read_u32 = |p: usize| {
+		let mut b = [0; 4];
+		b.copy_from_slice(&delta[p..p + 4]);
+		u32::from_le_bytes(b) as usize
+	};
+	while pos < delta.len() {
+		i += read_u32(pos);
+		let len = read_u32(pos + 4);
+		pos += 8;
+		for j in 0..len {
+			ret[i + j] ^= delta[pos + j];
+		}
+		i += len;
+		pos += len;
+	}
+	return ret;
+}
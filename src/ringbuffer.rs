@@ -1,4 +1,4 @@
-pub struct RingBuffer<T:Copy> {
+pub struct RingBuffer<T:Clone> {
 	wp: usize,
 	rp: usize,
 	remain: usize,
@@ -6,7 +6,7 @@ pub struct RingBuffer<T:Copy> {
 	data: Vec<T>
 }
 
-impl<T:Copy> RingBuffer<T> {
+impl<T:Clone> RingBuffer<T> {
 	pub fn new(cap: usize, v:T) -> RingBuffer<T> {
 		RingBuffer {
 			wp: 0,
@@ -33,7 +33,7 @@ impl<T:Copy> RingBuffer<T> {
 
 	pub fn read(&mut self) -> Option<T> {
 		if self.remain > 0 {
-			let t = self.data[self.rp];
+			let t = self.data[self.rp].clone();
 			self.rp += 1;
 			if self.rp >= self.cap {
 				self.rp = 0;
@@ -44,4 +44,42 @@ impl<T:Copy> RingBuffer<T> {
 			return None;
 		}
 	}
+
+	// Write, discarding the oldest entry when full
+	pub fn write_overwrite(&mut self, v:T) {
+		if self.remain >= self.cap {
+			self.rp += 1;
+			if self.rp >= self.cap {
+				self.rp = 0;
+			}
+			self.remain -= 1;
+		}
+		self.write(v);
+	}
+
+	pub fn len(&self) -> usize {
+		return self.remain;
+	}
+
+	pub fn clear(&mut self) {
+		self.wp = 0;
+		self.rp = 0;
+		self.remain = 0;
+	}
+}
+
+impl<T:Clone + Default> RingBuffer<T> {
+	// Take the newest entry, its slot is left with the default value
+	pub fn read_back(&mut self) -> Option<T> {
+		if self.remain > 0 {
+			if self.wp == 0 {
+				self.wp = self.cap;
+			}
+			self.wp -= 1;
+			self.remain -= 1;
+			return Some(std::mem::take(&mut self.data[self.wp]));
+		} else {
+			return None;
+		}
+	}
 }
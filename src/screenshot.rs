@@ -0,0 +1,91 @@
+use std::io;
+use std::io::Write;
+
+// Image dump of an RGB888 framebuffer without external dependencies.
+
+pub fn write_ppm(w: &mut impl Write, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+	write!(w, "P6\n{} {}\n255\n", width, height)?;
+	w.write_all(&rgb[0 .. width * height * 3])?;
+	return Ok(());
+}
+
+// PNG with uncompressed (stored) deflate blocks
+pub fn write_png(w: &mut impl Write, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+	w.write_all(&[0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A])?;
+
+	let mut ihdr = Vec::new();
+	ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+	ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+	ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8bit, RGB, deflate, no filter, no interlace
+	write_chunk(w, b"IHDR", &ihdr)?;
+
+	// each scanline is prefixed with filter type 0
+	let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+	for y in 0..height {
+		raw.push(0);
+		raw.extend_from_slice(&rgb[y * width * 3 .. (y + 1) * width * 3]);
+	}
+
+	let mut zlib = vec![0x78, 0x01];
+	let mut blocks = raw.chunks(0xFFFF).peekable();
+	while let Some(block) = blocks.next() {
+		let last = if blocks.peek().is_none() { 1 } else { 0 };
+		let len = block.len() as u16;
+		zlib.push(last);
+		zlib.extend_from_slice(&len.to_le_bytes());
+		zlib.extend_from_slice(&(!len).to_le_bytes());
+		zlib.extend_from_slice(block);
+	}
+	zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+	write_chunk(w, b"IDAT", &zlib)?;
+
+	write_chunk(w, b"IEND", &[])?;
+	return Ok(());
+}
+
+// FNV-1a 64bit. Stable across runs and platforms, used to compare frames.
+pub fn frame_hash(data: &[u8]) -> u64 {
+	let mut h: u64 = 0xCBF29CE484222325;
+	for b in data {
+		h ^= *b as u64;
+		h = h.wrapping_mul(0x100000001B3);
+	}
+	return h;
+}
+
+fn write_chunk(w: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+	w.write_all(&(data.len() as u32).to_be_bytes())?;
+	w.write_all(kind)?;
+	w.write_all(data)?;
+	let crc = crc32(&[&kind[..], data]);
+	w.write_all(&crc.to_be_bytes())?;
+	return Ok(());
+}
+
+// CRC-32 (IEEE) over the concatenated parts
+pub fn crc32(parts: &[&[u8]]) -> u32 {
+	let mut crc: u32 = 0xFFFFFFFF;
+	for part in parts {
+		for b in part.iter() {
+			crc ^= *b as u32;
+			for _ in 0..8 {
+				if crc & 1 != 0 {
+					crc = (crc >> 1) ^ 0xEDB88320;
+				} else {
+					crc >>= 1;
+				}
+			}
+		}
+	}
+	return !crc;
+}
+
+fn adler32(data: &[u8]) -> u32 {
+	let mut a: u32 = 1;
+	let mut b: u32 = 0;
+	for v in data {
+		a = (a + *v as u32) % 65521;
+		b = (b + a) % 65521;
+	}
+	return (b << 16) | a;
+}
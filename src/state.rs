@@ -0,0 +1,140 @@
+use std::io;
+
+// Save state format
+//   "RNSS" + u32 version, followed by NES, CPU, PPU, APU, MMU (with mapper) and Pad.
+//   All values are little endian. Byte arrays are prefixed with a u32 length.
+pub const STATE_MAGIC: [u8; 4] = [0x52, 0x4E, 0x53, 0x53]; // "RNSS"
+pub const STATE_VERSION: u32 = 13;
+
+pub struct StateWriter {
+	buf: Vec<u8>,
+}
+
+pub struct StateReader<'a> {
+	buf: &'a [u8],
+	pos: usize,
+}
+
+impl StateWriter {
+	pub fn new() -> StateWriter {
+		StateWriter {
+			buf: Vec::new(),
+		}
+	}
+
+	pub fn write_u8(&mut self, v: u8) {
+		self.buf.push(v);
+	}
+
+	pub fn write_bool(&mut self, v: bool) {
+		self.buf.push(v as u8);
+	}
+
+	pub fn write_u16(&mut self, v: u16) {
+		self.buf.extend_from_slice(&v.to_le_bytes());
+	}
+
+	pub fn write_u32(&mut self, v: u32) {
+		self.buf.extend_from_slice(&v.to_le_bytes());
+	}
+
+	pub fn write_u64(&mut self, v: u64) {
+		self.buf.extend_from_slice(&v.to_le_bytes());
+	}
+
+	pub fn write_i32(&mut self, v: i32) {
+		self.buf.extend_from_slice(&v.to_le_bytes());
+	}
+
+	pub fn write_usize(&mut self, v: usize) {
+		self.buf.extend_from_slice(&(v as u64).to_le_bytes());
+	}
+
+	pub fn write_f32(&mut self, v: f32) {
+		self.buf.extend_from_slice(&v.to_bits().to_le_bytes());
+	}
+
+	pub fn write_bytes(&mut self, v: &[u8]) {
+		self.write_u32(v.len() as u32);
+		self.buf.extend_from_slice(v);
+	}
+
+	pub fn into_vec(self) -> Vec<u8> {
+		self.buf
+	}
+}
+
+impl<'a> StateReader<'a> {
+	pub fn new(buf: &'a [u8]) -> StateReader<'a> {
+		StateReader {
+			buf: buf,
+			pos: 0,
+		}
+	}
+
+	fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+		if self.pos + n > self.buf.len() {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated save state"));
+		}
+		let ret = &self.buf[self.pos .. self.pos + n];
+		self.pos += n;
+		return Ok(ret);
+	}
+
+	pub fn read_u8(&mut self) -> io::Result<u8> {
+		return Ok(self.take(1)?[0]);
+	}
+
+	pub fn read_bool(&mut self) -> io::Result<bool> {
+		return Ok(self.take(1)?[0] != 0);
+	}
+
+	pub fn read_u16(&mut self) -> io::Result<u16> {
+		let mut b = [0; 2];
+		b.copy_from_slice(self.take(2)?);
+		return Ok(u16::from_le_bytes(b));
+	}
+
+	pub fn read_u32(&mut self) -> io::Result<u32> {
+		let mut b = [0; 4];
+		b.copy_from_slice(self.take(4)?);
+		return Ok(u32::from_le_bytes(b));
+	}
+
+	pub fn read_u64(&mut self) -> io::Result<u64> {
+		let mut b = [0; 8];
+		b.copy_from_slice(self.take(8)?);
+		return Ok(u64::from_le_bytes(b));
+	}
+
+	pub fn read_i32(&mut self) -> io::Result<i32> {
+		let mut b = [0; 4];
+		b.copy_from_slice(self.take(4)?);
+		return Ok(i32::from_le_bytes(b));
+	}
+
+	pub fn read_usize(&mut self) -> io::Result<usize> {
+		let mut b = [0; 8];
+		b.copy_from_slice(self.take(8)?);
+		return Ok(u64::from_le_bytes(b) as usize);
+	}
+
+	pub fn read_f32(&mut self) -> io::Result<f32> {
+		return Ok(f32::from_bits(self.read_u32()?));
+	}
+
+	// Reads a byte array into v. The stored length must match.
+	pub fn read_bytes(&mut self, v: &mut [u8]) -> io::Result<()> {
+		let len = self.read_u32()? as usize;
+		if len != v.len() {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "save state size mismatch"));
+		}
+		v.copy_from_slice(self.take(len)?);
+		return Ok(());
+	}
+
+	pub fn read_vec(&mut self) -> io::Result<Vec<u8>> {
+		let len = self.read_u32()? as usize;
+		return Ok(self.take(len)?.to_vec());
+	}
+}
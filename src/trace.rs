@@ -0,0 +1,116 @@
+use std::io;
+use std::io::BufRead;
+
+use crate::cpu::*;
+use crate::mmu::*;
+use crate::disasm::*;
+use crate::disasm::Mode::*;
+
+// Instruction trace in nestest.log format
+//   C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+// Memory operands are annotated with their effective address and value.
+// Values are read with MMU::peek, so tracing has no side effects on I/O registers.
+
+pub fn trace_line(mmu: &MMU, r: &Registers, line: u32, dot: u32, cycles: u64) -> String {
+	let pc = r.pc;
+	let op = &OPCODES[mmu.peek(pc) as usize];
+	let len = op.mode.len();
+
+	let mut raw = Vec::new();
+	for i in 0..len {
+		raw.push(format!("{:02X}", mmu.peek(pc.wrapping_add(i))));
+	}
+
+	let (text, _) = disassemble(mmu, pc);
+	let text = text.trim_start_matches('*').to_string();
+	let text = text + &annotation(mmu, r, op);
+	let star = if op.official { ' ' } else { '*' };
+
+	return format!("{:04X}  {:<8} {}{:<31} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+		pc, raw.join(" "), star, text, r.a, r.x, r.y, r.p, r.sp, line, dot, cycles);
+}
+
+fn annotation(mmu: &MMU, r: &Registers, op: &Opcode) -> String {
+	let lo = mmu.peek(r.pc.wrapping_add(1));
+	let word = (lo as u16) | ((mmu.peek(r.pc.wrapping_add(2)) as u16) << 8);
+	let read_word_zp = |zp: u8| (mmu.peek(zp as u16) as u16) | ((mmu.peek(zp.wrapping_add(1) as u16) as u16) << 8);
+
+	match op.mode {
+		ZP => format!(" = {:02X}", mmu.peek(lo as u16)),
+		ZPX | ZPY => {
+			let index = if op.mode == ZPX { r.x } else { r.y };
+			let ea = lo.wrapping_add(index);
+			format!(" @ {:02X} = {:02X}", ea, mmu.peek(ea as u16))
+		}
+		ABS => {
+			if op.name == "JMP" || op.name == "JSR" {
+				"".to_string()
+			} else {
+				format!(" = {:02X}", mmu.peek(word))
+			}
+		}
+		ABX | ABY => {
+			let index = if op.mode == ABX { r.x } else { r.y };
+			let ea = word.wrapping_add(index as u16);
+			format!(" @ {:04X} = {:02X}", ea, mmu.peek(ea))
+		}
+		IND => {
+			// page wrap bug of JMP ($xxFF)
+			let hi_addr = (word & 0xFF00) | (word.wrapping_add(1) & 0x00FF);
+			let target = (mmu.peek(word) as u16) | ((mmu.peek(hi_addr) as u16) << 8);
+			format!(" = {:04X}", target)
+		}
+		IZX => {
+			let zp = lo.wrapping_add(r.x);
+			let ea = read_word_zp(zp);
+			format!(" @ {:02X} = {:04X} = {:02X}", zp, ea, mmu.peek(ea))
+		}
+		IZY => {
+			let base = read_word_zp(lo);
+			let ea = base.wrapping_add(r.y as u16);
+			format!(" = {:04X} @ {:04X} = {:02X}", base, ea, mmu.peek(ea))
+		}
+		_ => "".to_string(),
+	}
+}
+
+pub struct TraceDiff {
+	pub line: usize,      // 1 origin
+	pub ours: String,     // empty if our log is shorter
+	pub reference: String,
+}
+
+// First line that differs. Lines beyond the end of the reference are not compared.
+//   registers_only: compare PC and A/X/Y/P/SP only
+pub fn compare_trace(ours: impl BufRead, reference: impl BufRead, registers_only: bool) -> io::Result<Option<TraceDiff>> {
+	let mut ours = ours.lines();
+	for (n, reference) in reference.lines().enumerate() {
+		let reference = reference?;
+		let line = match ours.next() {
+			None => {
+				return Ok(Some(TraceDiff { line: n + 1, ours: "".to_string(), reference: reference }));
+			}
+			Some(l) => l?,
+		};
+
+		let same = if registers_only {
+			registers(&line) == registers(&reference)
+		} else {
+			line.trim_end() == reference.trim_end()
+		};
+		if !same {
+			return Ok(Some(TraceDiff { line: n + 1, ours: line, reference: reference }));
+		}
+	}
+	return Ok(None);
+}
+
+// "PC A:.. X:.. Y:.. P:.. SP:.."
+fn registers(line: &str) -> String {
+	let pc = line.get(0..4).unwrap_or("");
+	let regs = match line.find("A:") {
+		Some(i) => line[i..].split_whitespace().take(5).collect::<Vec<&str>>().join(" "),
+		None => "".to_string(),
+	};
+	return format!("{} {}", pc, regs);
+}